@@ -33,10 +33,10 @@ fn main() {
         cursor.get_trail(i).expect("Could not read trail");
 
         while let Some(event) = cursor.next() {
-            print!("{:?}, [ timestamp={}", uuid, event.timestamp);
-            for (j, item) in event.items.iter().enumerate() {
-                let value = db.get_item_value(*item).unwrap();
-                print!(" {}={}", fields[j], value)
+            let record = event.decode(&db).expect("Could not decode event");
+            print!("{:?}, [ timestamp={}", uuid, record.timestamp);
+            for (field, value) in record.iter() {
+                print!(" {}={}", field, value)
             }
             println!(" ]");
         }
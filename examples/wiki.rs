@@ -12,11 +12,10 @@ fn main() {
     // iterate through some of the events
     for mut trail in db.iter() {
         let id = trail.id;
-        if let Some(event) = trail.next() {
-            let mut prev_time = event.timestamp;
+        if let Some(mut prev_time) = trail.next().map(|event| event.timestamp) {
             let mut session_cnt = 1;
             let mut event_cnt = 1;
-            for event in trail {
+            while let Some(event) = trail.next() {
                 if event.timestamp - prev_time > SESSION_LIMIT {
                     session_cnt += 1;
                 }
@@ -11,13 +11,9 @@ fn table_from_event(event: Event, header: Row, db: &Db) -> Table {
     table.add_row(header);
     let mut row_cells = Vec::new();
     row_cells.push(Cell::new(&format!("{}", event.timestamp)));
-    for item in event.items {
-        match db.get_item_value(*item) {
-            Some(item) => {
-                let cell = Cell::new(item);
-                let _ = &row_cells.push(cell);
-            },
-            _ => {},
+    if let Ok(record) = event.decode(db) {
+        for (_, value) in record.iter() {
+            row_cells.push(Cell::new(value));
         }
     }
     table.add_row(Row::new(row_cells));
@@ -39,9 +35,9 @@ fn main() {
     let header_row = header_from_db(&db);
 
     // iterate through trails (unique users)
-    for trail in db.iter() {
+    for mut trail in db.iter() {
         // iterate through the users wikipedia edits
-        for event in trail {
+        while let Some(event) = trail.next() {
             let table = table_from_event(event, header_row.clone(), &db);
             println!("{}", table);
         }
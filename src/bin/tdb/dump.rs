@@ -0,0 +1,255 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use traildb::{Db, EventFilter, Field, TrailId, Trails};
+
+use options::{DataFormat, Options};
+use {parse_uuids, uuid_to_hex};
+
+/// What a column of the output holds.
+enum Column {
+    Uuid,
+    Time,
+    Field(Field),
+}
+
+/// `tdb dump`: write the events of a TrailDB as CSV or JSON lines.
+pub fn run(opts: &Options) -> Result<(), String> {
+    let input = opts.input.as_deref().unwrap_or("a");
+    if input == "-" {
+        return Err("cannot read a TrailDB from stdin".to_owned());
+    }
+    let db = Db::open(input).map_err(|e| format!("opening a TrailDB at {} failed: {}", input, e))?;
+
+    let output = opts.output.as_deref().unwrap_or("-");
+    let out: Box<dyn Write> = if output == "-" {
+        Box::new(io::stdout())
+    } else {
+        if Path::new(output).exists() {
+            return Err(format!("output file {} already exists", output));
+        }
+        Box::new(File::create(output).map_err(|e| format!("could not open output file {}: {}", output, e))?)
+    };
+    let mut out = BufWriter::new(out);
+    dump(&db, opts, &mut out)?;
+    out.flush().map_err(|e| format!("writing to {} failed: {}", output, e))
+}
+
+/// Dump the events of `db` selected by `opts` to `out`.
+fn dump<W: Write>(db: &Db, opts: &Options, out: &mut W) -> Result<(), String> {
+    let filter = match opts.filter {
+        Some(ref expr) => Some(EventFilter::parse(db, expr).map_err(|e| format!("invalid filter: {}", e))?),
+        None => None,
+    };
+    let columns = columns(db, opts.fields.as_deref())?;
+    let mut dumper = Dumper {
+        db,
+        opts,
+        filter: filter.as_ref(),
+        names: columns.iter().map(|(name, _)| name.as_str()).collect(),
+        field_columns: vec![None; db.num_fields() as usize],
+        has_uuid: None,
+        has_time: None,
+    };
+    for (i, (_, column)) in columns.iter().enumerate() {
+        match *column {
+            Column::Uuid => dumper.has_uuid = Some(i),
+            Column::Time => dumper.has_time = Some(i),
+            Column::Field(field) => dumper.field_columns[field as usize] = Some(i),
+        }
+    }
+
+    let written = match opts.uuids {
+        Some(ref spec) => {
+            let uuids = parse_uuids(spec)?;
+            if opts.verbose {
+                eprintln!("Dumping {} trails.", uuids.len());
+            }
+            dumper.dump(db.trails_for_uuids(&uuids), out)
+        }
+        None => dumper.dump(db.trails(), out),
+    };
+    written.map_err(|e| format!("writing events failed: {}", e))
+}
+
+/// The columns named in `--fields`, or the UUID and all fields of `db`.
+fn columns(db: &Db, fields: Option<&str>) -> Result<Vec<(String, Column)>, String> {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => {
+            let mut columns = vec![("uuid".to_owned(), Column::Uuid), ("time".to_owned(), Column::Time)];
+            for field in 1..db.num_fields() as Field {
+                let name = db.get_field_name(field).unwrap_or_default();
+                columns.push((name.to_owned(), Column::Field(field)));
+            }
+            return Ok(columns);
+        }
+    };
+    if fields.contains(':') {
+        return Err("field indices in --fields are not supported with dump".to_owned());
+    }
+    fields.split(',')
+        .map(|name| {
+            let column = match name {
+                "uuid" => Column::Uuid,
+                "time" => Column::Time,
+                _ => Column::Field(db.get_field(name).map_err(|_| format!("field not found: '{}'", name))?),
+            };
+            Ok((name.to_owned(), column))
+        })
+        .collect()
+}
+
+struct Dumper<'a> {
+    db: &'a Db,
+    opts: &'a Options,
+    filter: Option<&'a EventFilter>,
+    names: Vec<&'a str>,
+    /// The output column of every field of the database.
+    field_columns: Vec<Option<usize>>,
+    has_uuid: Option<usize>,
+    has_time: Option<usize>,
+}
+
+impl<'a> Dumper<'a> {
+    fn dump<I, W>(&self, mut trails: Trails<'a, I>, out: &mut W) -> io::Result<()>
+        where I: Iterator<Item = TrailId>,
+              W: Write
+    {
+        if self.opts.format == DataFormat::Csv && self.opts.csv_header {
+            self.write_csv(out, &self.names.iter().map(|name| name.as_bytes()).collect::<Vec<_>>())?;
+        }
+        while let Some((id, cursor)) = trails.next_trail() {
+            if let Some(filter) = self.filter {
+                cursor.set_filter(filter).map_err(io::Error::other)?;
+            }
+            if cursor.peek().is_none() {
+                continue;
+            }
+            let uuid = uuid_to_hex(self.db.get_uuid(id).expect("trail id out of range"));
+            while let Some(event) = cursor.next() {
+                let timestamp = event.timestamp.to_string();
+                let mut values: Vec<&[u8]> = vec![b""; self.names.len()];
+                if let Some(i) = self.has_uuid {
+                    values[i] = uuid.as_bytes();
+                }
+                if let Some(i) = self.has_time {
+                    values[i] = timestamp.as_bytes();
+                }
+                for &item in event.items {
+                    if let Some(&Some(i)) = self.field_columns.get(item.field() as usize) {
+                        values[i] = self.db.get_item_bytes(item).unwrap_or_default();
+                    }
+                }
+                match self.opts.format {
+                    DataFormat::Csv => self.write_csv(out, &values)?,
+                    DataFormat::Json => self.write_json(out, &values)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_csv<W: Write>(&self, out: &mut W, values: &[&[u8]]) -> io::Result<()> {
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                out.write_all(&[self.opts.delimiter])?;
+            }
+            out.write_all(value)?;
+        }
+        out.write_all(b"\n")
+    }
+
+    fn write_json<W: Write>(&self, out: &mut W, values: &[&[u8]]) -> io::Result<()> {
+        out.write_all(b"{")?;
+        let mut first = true;
+        for (name, value) in self.names.iter().zip(values) {
+            if value.is_empty() && self.opts.json_no_empty {
+                continue;
+            }
+            if !first {
+                out.write_all(b", ")?;
+            }
+            first = false;
+            write_json_string(out, name.as_bytes())?;
+            out.write_all(b": ")?;
+            write_json_string(out, value)?;
+        }
+        out.write_all(b"}\n")
+    }
+}
+
+/// Write `value` as a JSON string. Invalid UTF-8 is replaced.
+fn write_json_string<W: Write>(out: &mut W, value: &[u8]) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(value).chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use traildb::{Constructor, Db};
+
+    use options::{DataFormat, Options};
+    use super::dump;
+    use uuid_to_hex;
+
+    #[test]
+    fn dump_csv_and_json() {
+        let tmp_dir = TempDir::new("tdb_dump").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["author", "title"]).unwrap();
+        let uuid = [7; 16];
+        assert!(cons.add(&uuid, 1, &["Asimov", "Foundation"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["Herbert", ""]).is_ok());
+        assert!(cons.add(&[8; 16], 3, &["Le Guin", "The \"Dispossessed\""]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        let hex = uuid_to_hex(&uuid);
+
+        let run = |opts: &Options| -> Result<String, String> {
+            let mut out = vec![];
+            dump(&db, opts, &mut out)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+
+        let mut opts = Options {
+            fields: Some("uuid,time,author,title".to_owned()),
+            uuids: Some(hex.clone()),
+            csv_header: true,
+            delimiter: b',',
+            ..Options::default()
+        };
+        assert_eq!(format!("uuid,time,author,title\n{0},1,Asimov,Foundation\n{0},2,Herbert,\n", hex),
+                   run(&opts).unwrap());
+
+        opts.format = DataFormat::Json;
+        opts.fields = Some("time,title".to_owned());
+        opts.uuids = None;
+        opts.json_no_empty = true;
+        opts.filter = Some("author!=Asimov".to_owned());
+        let mut lines: Vec<String> = run(&opts).unwrap().lines().map(str::to_owned).collect();
+        lines.sort();
+        assert_eq!(vec![r#"{"time": "2"}"#, r#"{"time": "3", "title": "The \"Dispossessed\""}"#], lines);
+
+        opts.fields = Some("time,price".to_owned());
+        assert_eq!(Err("field not found: 'price'".to_owned()), run(&opts));
+        opts.fields = None;
+        opts.filter = Some("price=1".to_owned());
+        assert!(run(&opts).unwrap_err().starts_with("invalid filter: unknown field `price`"));
+    }
+}
@@ -0,0 +1,145 @@
+//! `tdb`, a command line tool for TrailDBs modelled on the `tdb` tool
+//! that ships with the TrailDB C library.
+
+extern crate traildb;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use traildb::Uuid;
+
+extern crate serde_json;
+
+mod dump;
+mod make;
+mod merge;
+mod options;
+
+use options::Options;
+
+const USAGE: &str = "\
+tdb - a command line interface for manipulating TrailDBs
+
+USAGE:
+tdb <command> [options] [args]
+
+Command is one of the following:
+make    create a TrailDB
+dump    dump an existing TrailDB to an output file
+merge   merges a set of TrailDBs into a new TrailDB
+
+OPTIONS:
+-c --csv          read input as CSV or output CSV (default)
+-d --delimiter    CSV delimiter (default: ' ')
+-j --json         read input as JSON or output JSON
+                   the format is one JSON-encoded event (object) per line
+-i --input        read input from the given file:
+                   for 'make' this is the source of input events
+                    (default: stdin)
+                   for 'dump' this is the TrailDB to be dumped
+                    (default: a.tdb)
+                   for 'merge' this is not supported
+                    give a list of tdbs as args
+-o --output       write output to the given file:
+                   for 'make' this is the TrailDB to be created
+                    (default: a.tdb)
+                   for 'dump' this is the output file for events
+                    (default: stdout)
+                   for 'merge' this is the TrailDB to be created
+                    (default: a.tdb)
+-u --uuids        dump or merge only these trails: a comma-separated list
+                   of hex-encoded UUIDs, or @file with one UUID per line.
+                   Invalid or missing UUIDs are ignored.
+-f --fields       field specification -- see below for details
+-F --filter       dump or merge only events matching the filter, e.g.
+                   'author=Asimov & name=Foundation name=@book_name & price!='
+--csv-header      read fields from the CSV header or output the field
+                   names as the first CSV row
+--json-no-empty   don't output empty values to JSON output
+--skip-bad-input  don't quit on malformed input lines, skip them
+--tdb-format      TrailDB output format:
+                   'pkg' for the default one-file format,
+                   'dir' for a directory
+--no-bigrams      when building TrailDBs, do not build and compress with bigrams
+-v --verbose      print diagnostic output to stderr
+
+FIELD SPECIFICATION:
+make:
+1) if no --fields is specified, two fields are expected, 'uuid' and 'time'
+2) --fields uuid,time,field2,field3,...
+     - CSV columns, or JSON keys, to read in this order
+3) --fields 2:uuid,5:time,30:field3,102:field4
+     - maps the specified CSV column IDs to TrailDB fields
+4) --csv-header
+     - like 2) but reads the field names from the first row of the input
+'uuid' and 'time' must always be specified. UUIDs are hex-encoded,
+shorter ones are padded with zeros.
+
+dump:
+1) if no --fields is specified, all fields are output.
+2) --fields uuid,time,field2,field3,...
+     - outputs only the specified fields from TrailDB
+
+merge:
+The new TrailDB has the fields of all inputs. Events of an input that
+lacks a field get an empty value for it.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => usage_and_exit(),
+    };
+    let result = match command {
+        "make" => Options::parse(&args[1..]).and_then(|opts| make::run(&opts)),
+        "dump" => Options::parse(&args[1..]).and_then(|opts| dump::run(&opts)),
+        "merge" => Options::parse(&args[1..]).and_then(|opts| merge::run(&opts)),
+        "-h" | "--help" | "help" => {
+            print!("{}", USAGE);
+            return;
+        }
+        _ => usage_and_exit(),
+    };
+    if let Err(message) = result {
+        eprintln!("tdb {}: {}", command, message);
+        process::exit(1);
+    }
+}
+
+fn usage_and_exit() -> ! {
+    eprint!("{}", USAGE);
+    process::exit(1);
+}
+
+/// Encode a UUID as 32 lowercase hex digits.
+pub fn uuid_to_hex(uuid: &Uuid) -> String {
+    uuid.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode 32 hex digits to a UUID.
+pub fn uuid_from_hex(hex: &str) -> Option<Uuid> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+    let mut uuid = [0; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// Parse a `--uuids` argument: a comma-separated list of UUIDs, or
+/// `@path` to read one UUID per line. Invalid UUIDs are skipped.
+pub fn parse_uuids(spec: &str) -> Result<Vec<Uuid>, String> {
+    let contents;
+    let uuids: Vec<&str> = match spec.strip_prefix('@') {
+        Some(path) => {
+            contents = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+            contents.lines().collect()
+        }
+        None => spec.split(',').collect(),
+    };
+    Ok(uuids.into_iter().filter_map(|uuid| uuid_from_hex(uuid.trim())).collect())
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::str;
+
+use serde_json::{self, Value};
+use traildb::{Constructor, Timestamp, Uuid};
+
+use options::{DataFormat, Options};
+use uuid_from_hex;
+
+/// Where a column of the input goes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Target {
+    Uuid,
+    Time,
+    Field(usize),
+}
+
+/// `tdb make`: build a TrailDB from CSV or JSON lines.
+pub fn run(opts: &Options) -> Result<(), String> {
+    let input = opts.input.as_deref().unwrap_or("-");
+    let mut input: Box<dyn BufRead> = if input == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(input).map_err(|e| format!("could not open input file {}: {}", input, e))?))
+    };
+
+    let output = opts.new_tdb_output()?;
+    make(&mut input, Path::new(output), opts, &mut io::stderr())
+}
+
+/// Build a TrailDB at `output` from the events in `input`. Bad lines
+/// skipped with `--skip-bad-input` are reported to `log`.
+fn make<R: BufRead, L: Write>(input: &mut R, output: &Path, opts: &Options, log: &mut L) -> Result<(), String> {
+    // Line numbers count the header, so they match the input file.
+    let mut lineno = 0;
+    let mut header = vec![];
+    let columns = if opts.csv_header && opts.format == DataFormat::Csv {
+        input.read_until(b'\n', &mut header).map_err(|e| format!("could not read header line: {}", e))?;
+        if header.pop() != Some(b'\n') || header.is_empty() {
+            return Err("could not read header line".to_owned());
+        }
+        lineno += 1;
+        let header = str::from_utf8(&header).map_err(|_| "the header line is not valid UTF-8".to_owned())?;
+        let names = header.split(opts.delimiter as char).enumerate().map(|(i, name)| (i + 1, name));
+        Columns::new(names, "input")?
+    } else {
+        Columns::new(fields_arg(opts.fields.as_deref())?, "--fields")?
+    };
+
+    let mut cons = Constructor::with_options(output, &columns.fields, &opts.constructor_options())
+        .map_err(|e| format!("opening a new TrailDB at {} failed: {}", output.display(), e))?;
+
+    let mut maker = Maker {
+        cons: &mut cons,
+        opts,
+        columns: &columns,
+        log,
+    };
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line).map_err(|e| format!("reading input failed: {}", e))? == 0 {
+            break;
+        }
+        lineno += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let added = match opts.format {
+            DataFormat::Csv => maker.add_csv(&line),
+            DataFormat::Json => maker.add_json(&line, lineno)?,
+        };
+        if let Err(message) = added {
+            maker.bad_input(&format!("Line {}: {}", lineno, message))?;
+        }
+    }
+
+    cons.finalize().map_err(|e| format!("finalizing a new TrailDB at {} failed: {}", output.display(), e))
+}
+
+/// The (1-based) column indices and names given in `--fields`, which
+/// default to `uuid,time`.
+fn fields_arg(fields: Option<&str>) -> Result<Vec<(usize, &str)>, String> {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return Ok(vec![(1, "uuid"), (2, "time")]),
+    };
+    if !fields.contains(':') {
+        return Ok(fields.split(',').enumerate().map(|(i, name)| (i + 1, name)).collect());
+    }
+    fields.split(',')
+        .map(|field| {
+            let mut parts = field.splitn(2, ':');
+            let idx = parts.next().unwrap_or_default();
+            let name = parts.next().ok_or_else(|| "specify a field index for all fields in --fields".to_owned())?;
+            match idx.parse() {
+                Ok(idx) if idx > 0 => Ok((idx, name)),
+                _ => Err(format!("invalid field index: {}", idx)),
+            }
+        })
+        .collect()
+}
+
+/// The mapping from input columns or keys to the fields of the new
+/// TrailDB.
+struct Columns<'a> {
+    /// The target of every CSV column.
+    targets: Vec<Option<Target>>,
+    /// The target of every JSON key.
+    keys: HashMap<&'a str, Target>,
+    /// The field names of the new TrailDB.
+    fields: Vec<&'a str>,
+}
+
+impl<'a> Columns<'a> {
+    fn new<I>(names: I, source: &str) -> Result<Columns<'a>, String>
+        where I: IntoIterator<Item = (usize, &'a str)>
+    {
+        let mut columns = Columns {
+            targets: vec![],
+            keys: HashMap::new(),
+            fields: vec![],
+        };
+        for (idx, name) in names {
+            let target = match name {
+                "uuid" => Target::Uuid,
+                "time" => Target::Time,
+                _ => {
+                    columns.fields.push(name);
+                    Target::Field(columns.fields.len() - 1)
+                }
+            };
+            if columns.targets.len() < idx {
+                columns.targets.resize(idx, None);
+            }
+            columns.targets[idx - 1] = Some(target);
+            columns.keys.insert(name, target);
+        }
+        for &name in &["uuid", "time"] {
+            if !columns.keys.contains_key(name) {
+                return Err(format!("field '{}' is missing in {}", name, source));
+            }
+        }
+        Ok(columns)
+    }
+}
+
+struct Maker<'a, L: 'a> {
+    cons: &'a mut Constructor,
+    opts: &'a Options,
+    columns: &'a Columns<'a>,
+    log: &'a mut L,
+}
+
+impl<'a, L: Write> Maker<'a, L> {
+    /// Add the event of a CSV line. Columns past the last mapped one are
+    /// ignored.
+    fn add_csv(&mut self, line: &[u8]) -> Result<(), String> {
+        let mut event = Event::new(self.columns.fields.len());
+        for (value, target) in line.split(|&b| b == self.opts.delimiter).zip(&self.columns.targets) {
+            if let Some(target) = *target {
+                event.set(target, value)?;
+            }
+        }
+        event.add(self.cons)
+    }
+
+    /// Add the event of a JSON line. Keys that are not fields are
+    /// ignored, as are null values. Malformed JSON is an error even with
+    /// `--skip-bad-input`.
+    fn add_json(&mut self, line: &[u8], lineno: u64) -> Result<Result<(), String>, String> {
+        let object = match serde_json::from_slice(line) {
+            Ok(Value::Object(object)) => object,
+            Ok(_) => return Err(format!("Line {}: Not a JSON object", lineno)),
+            Err(ref e) if e.is_eof() => return Err(format!("Line {}: Truncated JSON", lineno)),
+            Err(_) => return Err(format!("Line {}: Corrupted JSON", lineno)),
+        };
+        let mut values = Vec::with_capacity(object.len());
+        for (key, value) in &object {
+            if let Some(&target) = self.columns.keys.get(key.as_str()) {
+                let value = match *value {
+                    Value::Null => continue,
+                    Value::String(ref value) => value.clone(),
+                    Value::Bool(_) | Value::Number(_) => value.to_string(),
+                    Value::Array(_) | Value::Object(_) => {
+                        return Err(format!("Line {}: Invalid value in the JSON object", lineno))
+                    }
+                };
+                values.push((target, value));
+            }
+        }
+        let mut event = Event::new(self.columns.fields.len());
+        for &(target, ref value) in &values {
+            if let Err(message) = event.set(target, value.as_bytes()) {
+                return Ok(Err(message));
+            }
+        }
+        Ok(event.add(self.cons))
+    }
+
+    /// Report a bad line, or fail unless `--skip-bad-input` is given.
+    fn bad_input(&mut self, message: &str) -> Result<(), String> {
+        if !self.opts.skip_bad_input {
+            return Err(message.to_owned());
+        }
+        writeln!(self.log, "{}", message).map_err(|e| format!("writing to stderr failed: {}", e))
+    }
+}
+
+/// The values of one input line.
+struct Event<'v> {
+    uuid: Option<Uuid>,
+    timestamp: Option<Timestamp>,
+    values: Vec<&'v [u8]>,
+}
+
+impl<'v> Event<'v> {
+    fn new(num_fields: usize) -> Event<'v> {
+        Event {
+            uuid: None,
+            timestamp: None,
+            values: vec![b""; num_fields],
+        }
+    }
+
+    fn set(&mut self, target: Target, value: &'v [u8]) -> Result<(), String> {
+        match target {
+            Target::Uuid => self.uuid = Some(parse_uuid(value)?),
+            Target::Time => {
+                let timestamp = str::from_utf8(value).ok().and_then(|value| value.parse().ok());
+                self.timestamp = Some(timestamp.ok_or_else(|| {
+                    format!("Invalid timestamp '{}'", String::from_utf8_lossy(value))
+                })?);
+            }
+            Target::Field(field) => self.values[field] = value,
+        }
+        Ok(())
+    }
+
+    fn add(&self, cons: &mut Constructor) -> Result<(), String> {
+        let uuid = self.uuid.ok_or_else(|| "UUID missing".to_owned())?;
+        let timestamp = self.timestamp.ok_or_else(|| "Timestamp missing".to_owned())?;
+        cons.add_bytes(&uuid, timestamp, &self.values).map_err(|e| format!("Adding event failed: {}", e))
+    }
+}
+
+/// Parse a hex-encoded UUID. Shorter UUIDs are padded with zeros, like
+/// the C tool does.
+fn parse_uuid(value: &[u8]) -> Result<Uuid, String> {
+    let invalid = || format!("Invalid UUID '{}'", String::from_utf8_lossy(value));
+    if value.len() > 32 {
+        return Err(invalid());
+    }
+    let hex = str::from_utf8(value).map_err(|_| invalid())?;
+    uuid_from_hex(&format!("{:0<32}", hex)).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use traildb::Db;
+
+    use options::{DataFormat, Options};
+    use super::make;
+
+    #[test]
+    fn make_from_csv_and_json() {
+        let tmp_dir = TempDir::new("tdb_make").unwrap();
+        let run = |name: &str, input: &str, opts: &Options| -> Result<(Db, String), String> {
+            let path = tmp_dir.path().join(name);
+            let mut log = vec![];
+            make(&mut input.as_bytes(), &path, opts, &mut log)?;
+            Ok((Db::open(&path).unwrap(), String::from_utf8(log).unwrap()))
+        };
+        let events = |db: &Db| -> Vec<(u64, Vec<String>)> {
+            let mut events = vec![];
+            let mut trails = db.trails();
+            while let Some((_, cursor)) = trails.next_trail() {
+                while let Some(event) = cursor.next() {
+                    let values = event.items.iter().map(|&item| db.get_item_value(item).unwrap().unwrap_or_default().to_owned());
+                    events.push((event.timestamp, values.collect()));
+                }
+            }
+            events.sort();
+            events
+        };
+
+        let csv = "time,author,uuid,ignored\n\
+                   1,Asimov,0a,x\n\
+                   2,Herbert,0b\n\
+                   3,Le Guin,xyz\n\
+                   four,Tolkien,0b\n\
+                   5,Pratchett\n";
+        let mut opts = Options {
+            delimiter: b',',
+            csv_header: true,
+            skip_bad_input: true,
+            ..Options::default()
+        };
+        let (db, log) = run("csv", csv, &opts).unwrap();
+        assert_eq!(Some("ignored"), db.get_field_name(2));
+        assert!(db.get_trail_id(&[0x0a, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_some());
+        assert_eq!(vec![(1, vec!["Asimov".to_owned(), "x".to_owned()]), (2, vec!["Herbert".to_owned(), "".to_owned()])],
+                   events(&db));
+        assert_eq!("Line 4: Invalid UUID 'xyz'\nLine 5: Invalid timestamp 'four'\nLine 6: UUID missing\n", log);
+
+        opts.skip_bad_input = false;
+        assert_eq!(Err("Line 4: Invalid UUID 'xyz'".to_owned()), run("csv_strict", csv, &opts).map(|_| ()));
+        opts.csv_header = false;
+        assert_eq!(Err("field 'uuid' is missing in --fields".to_owned()),
+                   run("no_uuid", "", &Options { fields: Some("2:time".to_owned()), ..opts.clone() }).map(|_| ()));
+
+        let json = r#"{"uuid": "0a", "time": 1, "author": "Asimov", "year": 1951, "extra": [1]}
+                      {"uuid": "0b", "time": "2", "author": null, "year": true}
+                      {"uuid": "0c", "author": "Le Guin"}"#;
+        opts.format = DataFormat::Json;
+        opts.fields = Some("uuid,time,author,year".to_owned());
+        opts.skip_bad_input = true;
+        let (db, log) = run("json", json, &opts).unwrap();
+        assert_eq!(vec![(1, vec!["Asimov".to_owned(), "1951".to_owned()]), (2, vec!["".to_owned(), "true".to_owned()])],
+                   events(&db));
+        assert_eq!("Line 3: Timestamp missing\n", log);
+        assert_eq!(Err("Line 1: Not a JSON object".to_owned()), run("json_array", "[]", &opts).map(|_| ()));
+        assert_eq!(Err("Line 2: Truncated JSON".to_owned()),
+                   run("json_truncated", "{}\n{\"uuid\": ", &opts).map(|_| ()));
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use traildb::{merge_with_options, Db, DbOption, EventFilter, Uuid};
+
+use options::Options;
+use parse_uuids;
+
+/// `tdb merge`: merge the TrailDBs given as arguments into a new one.
+pub fn run(opts: &Options) -> Result<(), String> {
+    if opts.input.is_some() {
+        return Err("--input is not supported, give the TrailDBs to merge as arguments".to_owned());
+    }
+    if opts.args.is_empty() {
+        return Err("specify at least one input TrailDB".to_owned());
+    }
+    let output = opts.new_tdb_output()?;
+    let uuids = match opts.uuids {
+        Some(ref spec) => Some(parse_uuids(spec)?),
+        None => None,
+    };
+
+    let mut dbs = vec![];
+    for input in &opts.args {
+        let mut db = Db::open(input).map_err(|e| format!("opening a TrailDB at {} failed: {}", input, e))?;
+        apply_filter(&mut db, opts, uuids.as_deref()).map_err(|e| format!("{}: {}", input, e))?;
+        dbs.push(db);
+    }
+    merge_with_options(&dbs, output, &opts.constructor_options()).map_err(|e| format!("merging failed: {}", e))
+}
+
+/// Restrict `db` to the events matching `--filter`, in the trails
+/// selected by `--uuids`. Fields missing from `db` are empty in the
+/// merged TrailDB, so the filter treats them as empty too.
+fn apply_filter(db: &mut Db, opts: &Options, uuids: Option<&[Uuid]>) -> Result<(), String> {
+    let filter = match opts.filter {
+        Some(ref expr) => Some(Arc::new(EventFilter::parse_lenient(db, expr).map_err(|e| format!("invalid filter: {}", e))?)),
+        None => None,
+    };
+    let uuids = match uuids {
+        Some(uuids) => uuids,
+        None => return db.set_option(DbOption::EventFilter(filter)).map_err(|e| e.to_string()),
+    };
+    let filter = filter.unwrap_or_else(|| Arc::new(EventFilter::all()));
+    for uuid in uuids {
+        if let Some(trail_id) = db.get_trail_id(uuid) {
+            db.set_trail_filter(trail_id, filter.clone()).map_err(|e| e.to_string())?;
+        }
+    }
+    db.set_option(DbOption::EventFilter(Some(Arc::new(EventFilter::none())))).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use traildb::{Constructor, Db};
+
+    use options::Options;
+    use super::run;
+    use uuid_to_hex;
+
+    #[test]
+    fn merge_filtered() {
+        let tmp_dir = TempDir::new("tdb_merge").unwrap();
+        let mut args = vec![];
+        for (day, field) in ["page", "url"].iter().enumerate() {
+            let path = tmp_dir.path().join(format!("day{}", day));
+            let mut cons = Constructor::new(&path, &[field]).unwrap();
+            assert!(cons.add(&[1; 16], day as u64, &["a"]).is_ok());
+            assert!(cons.add(&[1; 16], day as u64 + 10, &["b"]).is_ok());
+            assert!(cons.add(&[2; 16], day as u64, &["a"]).is_ok());
+            assert!(cons.finalize().is_ok());
+            args.push(path.to_str().unwrap().to_owned());
+        }
+        let output = tmp_dir.path().join("merged");
+        let mut opts = Options {
+            output: Some(output.to_str().unwrap().to_owned()),
+            uuids: Some(uuid_to_hex(&[1; 16])),
+            filter: Some("url!=b".to_owned()),
+            args,
+            ..Options::default()
+        };
+        // Only the second day has a url field, the first day keeps all
+        // of its events
+        assert!(run(&opts).is_ok());
+        let events = |db: &Db| {
+            let mut cursor = db.cursor();
+            cursor.get_trail(0).unwrap();
+            let mut events = vec![];
+            while let Some(event) = cursor.next() {
+                let values: Vec<&str> = event.items.iter().map(|&item| db.get_item_value(item).unwrap().unwrap_or("")).collect();
+                events.push((event.timestamp, values.join(",")));
+            }
+            events
+        };
+        let db = Db::open(&output).unwrap();
+        assert_eq!(1, db.num_trails());
+        assert_eq!(vec![(0, "a,".to_owned()), (1, ",a".to_owned()), (10, "b,".to_owned())], events(&db));
+
+        let output = tmp_dir.path().join("merged_all");
+        opts.output = Some(output.to_str().unwrap().to_owned());
+        opts.filter = None;
+        assert!(run(&opts).is_ok());
+        let db = Db::open(&output).unwrap();
+        assert_eq!(1, db.num_trails());
+        assert_eq!(4, db.num_events());
+        assert_eq!(Some("url"), db.get_field_name(2));
+        assert!(run(&opts).unwrap_err().contains("already exists"));
+    }
+}
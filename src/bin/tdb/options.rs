@@ -0,0 +1,169 @@
+use std::path::Path;
+
+use traildb::{ConstructorOptions, Format};
+
+/// The format of events read or written by a command.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DataFormat {
+    Csv,
+    Json,
+}
+
+/// Command line options, shared by all commands.
+#[derive(Debug,Clone)]
+pub struct Options {
+    pub format: DataFormat,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub delimiter: u8,
+    pub fields: Option<String>,
+    pub uuids: Option<String>,
+    pub filter: Option<String>,
+    pub csv_header: bool,
+    pub json_no_empty: bool,
+    pub skip_bad_input: bool,
+    pub tdb_format: Option<Format>,
+    pub no_bigrams: bool,
+    pub verbose: bool,
+    /// Arguments that are not options.
+    pub args: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            format: DataFormat::Csv,
+            input: None,
+            output: None,
+            delimiter: b' ',
+            fields: None,
+            uuids: None,
+            filter: None,
+            csv_header: false,
+            json_no_empty: false,
+            skip_bad_input: false,
+            tdb_format: None,
+            no_bigrams: false,
+            verbose: false,
+            args: vec![],
+        }
+    }
+}
+
+impl Options {
+    /// Parse the arguments following the command name. Options taking
+    /// a value accept it as the next argument, attached to a short
+    /// option (`-d,`) or after `=` for long options (`--delimiter=,`).
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                opts.args.push(arg.clone());
+                continue;
+            }
+            let (name, inline_value) = if arg.starts_with("--") {
+                match arg.find('=') {
+                    Some(eq) => (&arg[..eq], Some(arg[eq + 1..].to_owned())),
+                    None => (arg.as_str(), None),
+                }
+            } else {
+                match arg.char_indices().nth(2) {
+                    Some((i, _)) => (&arg[..i], Some(arg[i..].to_owned())),
+                    None => (arg.as_str(), None),
+                }
+            };
+            let no_value = || -> Result<(), String> {
+                match inline_value {
+                    Some(_) => Err(format!("option {} does not take a value", name)),
+                    None => Ok(()),
+                }
+            };
+            let mut value = || -> Result<String, String> {
+                match inline_value.clone() {
+                    Some(value) => Ok(value),
+                    None => args.next().cloned().ok_or_else(|| format!("option {} requires a value", name)),
+                }
+            };
+            match name {
+                "-c" | "--csv" => {
+                    no_value()?;
+                    opts.format = DataFormat::Csv;
+                }
+                "-j" | "--json" => {
+                    no_value()?;
+                    opts.format = DataFormat::Json;
+                }
+                "-i" | "--input" => opts.input = Some(value()?),
+                "-o" | "--output" => opts.output = Some(value()?),
+                "-d" | "--delimiter" => {
+                    let delimiter = value()?;
+                    if delimiter.len() != 1 {
+                        return Err(format!("delimiter must be one character, not '{}'", delimiter));
+                    }
+                    opts.delimiter = delimiter.as_bytes()[0];
+                }
+                "-f" | "--fields" => opts.fields = Some(value()?),
+                "-u" | "--uuids" => opts.uuids = Some(value()?),
+                "-F" | "--filter" => opts.filter = Some(value()?),
+                "--csv-header" => {
+                    no_value()?;
+                    opts.csv_header = true;
+                }
+                "--json-no-empty" => {
+                    no_value()?;
+                    opts.json_no_empty = true;
+                }
+                "--skip-bad-input" => {
+                    no_value()?;
+                    opts.skip_bad_input = true;
+                }
+                "--tdb-format" => {
+                    opts.tdb_format = match value()?.as_str() {
+                        "pkg" => Some(Format::Package),
+                        "dir" => Some(Format::Directory),
+                        format => return Err(format!("invalid --tdb-format '{}', expected 'pkg' or 'dir'", format)),
+                    }
+                }
+                "--no-bigrams" => {
+                    no_value()?;
+                    opts.no_bigrams = true;
+                }
+                "-v" | "--verbose" => {
+                    no_value()?;
+                    opts.verbose = true;
+                }
+                _ => return Err(format!("unknown option {}", name)),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// The path of a TrailDB to create, `a` by default. The path must not
+    /// exist yet, with or without the package extension.
+    pub fn new_tdb_output(&self) -> Result<&str, String> {
+        let output = self.output.as_deref().unwrap_or("a");
+        if output == "-" {
+            return Err("cannot output a TrailDB to stdout".to_owned());
+        }
+        for path in &[output.to_owned(), format!("{}.tdb", output)] {
+            if Path::new(path).exists() {
+                return Err(format!("output file {} already exists", path));
+            }
+        }
+        Ok(output)
+    }
+
+    /// The options of a TrailDB to create, from `--tdb-format` and
+    /// `--no-bigrams`.
+    pub fn constructor_options(&self) -> ConstructorOptions {
+        let mut cons_opts = ConstructorOptions::new();
+        if let Some(format) = self.tdb_format {
+            cons_opts.output_format(format);
+        }
+        if self.no_bigrams {
+            cons_opts.no_bigrams(true);
+        }
+        cons_opts
+    }
+}
@@ -0,0 +1,321 @@
+use std::error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::slice;
+use std::str::FromStr;
+
+use serde::de::value::{BorrowedStrDeserializer, StrDeserializer, U64Deserializer};
+use serde::de::{self, Deserialize, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+
+use {Cursor, EventRecord, Field, Timestamp};
+
+/// The key the timestamp of an event is deserialized from, unless
+/// another one is given with `EventDeserializer::timestamp_key`. TrailDB
+/// reserves the field name `time` for timestamps.
+pub const TIMESTAMP_KEY: &str = "time";
+
+/// An error from deserializing an event.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DeserializeError {
+    message: String,
+    field: Option<String>,
+}
+
+impl DeserializeError {
+    /// What went wrong.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The field that was missing or could not be deserialized, if
+    /// known.
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    fn in_field(mut self, name: &str) -> DeserializeError {
+        if self.field.is_none() {
+            self.field = Some(name.to_owned());
+        }
+        self
+    }
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(ref field) = self.field {
+            write!(f, ", field `{}`", field)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> DeserializeError {
+        DeserializeError {
+            message: msg.to_string(),
+            field: None,
+        }
+    }
+
+    fn missing_field(field: &'static str) -> DeserializeError {
+        DeserializeError {
+            message: "missing value".to_owned(),
+            field: Some(field.to_owned()),
+        }
+    }
+}
+
+/// A `Deserializer` over a decoded event.
+///
+/// The event is presented as a map from field names to values, plus
+/// the timestamp under `TIMESTAMP_KEY`. Values are strings; numbers
+/// and booleans are parsed with `FromStr`, empty values deserialize as
+/// `None` and unit enum variants are matched by name.
+pub struct EventDeserializer<'r, 'de: 'r> {
+    record: &'r EventRecord<'de>,
+    timestamp_key: &'r str,
+}
+
+impl<'r, 'de> EventDeserializer<'r, 'de> {
+    pub fn new(record: &'r EventRecord<'de>) -> Self {
+        EventDeserializer {
+            record,
+            timestamp_key: TIMESTAMP_KEY,
+        }
+    }
+
+    /// Deserialize the timestamp from `key` instead of `time`.
+    pub fn timestamp_key(mut self, key: &'r str) -> Self {
+        self.timestamp_key = key;
+        self
+    }
+}
+
+impl<'r, 'de> de::Deserializer<'de> for EventDeserializer<'r, 'de> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_map(RecordAccess {
+            timestamp: Some((self.timestamp_key, self.record.timestamp)),
+            fields: self.record.fields.iter(),
+            pending: Pending::None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+enum Pending<'de> {
+    None,
+    Timestamp(Timestamp),
+    Value(&'de str, &'de str),
+}
+
+struct RecordAccess<'r, 'de: 'r> {
+    timestamp: Option<(&'r str, Timestamp)>,
+    fields: slice::Iter<'r, (Field, &'de str, &'de str)>,
+    pending: Pending<'de>,
+}
+
+impl<'r, 'de> MapAccess<'de> for RecordAccess<'r, 'de> {
+    type Error = DeserializeError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, DeserializeError>
+        where K: DeserializeSeed<'de>
+    {
+        if let Some((key, timestamp)) = self.timestamp.take() {
+            self.pending = Pending::Timestamp(timestamp);
+            let key: StrDeserializer<DeserializeError> = key.into_deserializer();
+            return seed.deserialize(key).map(Some);
+        }
+        match self.fields.next() {
+            None => Ok(None),
+            Some(&(_, name, value)) => {
+                self.pending = Pending::Value(name, value);
+                seed.deserialize(BorrowedStrDeserializer::new(name)).map(Some)
+            }
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, DeserializeError>
+        where V: DeserializeSeed<'de>
+    {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => Err(de::Error::custom("value requested before key")),
+            Pending::Timestamp(timestamp) => {
+                let value: U64Deserializer<DeserializeError> = timestamp.into_deserializer();
+                seed.deserialize(value)
+            }
+            Pending::Value(name, value) => {
+                seed.deserialize(ValueDeserializer { name, value }).map_err(|e| e.in_field(name))
+            }
+        }
+    }
+}
+
+/// Deserializes the value of a single field.
+struct ValueDeserializer<'de> {
+    name: &'de str,
+    value: &'de str,
+}
+
+impl<'de> ValueDeserializer<'de> {
+    fn parse<T>(&self) -> Result<T, DeserializeError>
+        where T: FromStr,
+              T::Err: fmt::Display
+    {
+        self.value.parse().map_err(|e| {
+            let msg = format!("invalid value `{}`: {}", self.value, e);
+            <DeserializeError as de::Error>::custom(msg).in_field(self.name)
+        })
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_borrowed_bytes(self.value.as_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        if self.value.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeserializeError>
+        where V: Visitor<'de>
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeserializeError>
+        where V: Visitor<'de>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(self,
+                           _name: &'static str,
+                           _variants: &'static [&'static str],
+                           visitor: V)
+                           -> Result<V::Value, DeserializeError>
+        where V: Visitor<'de>
+    {
+        let value: BorrowedStrDeserializer<DeserializeError> = BorrowedStrDeserializer::new(self.value);
+        visitor.visit_enum(value)
+    }
+
+    forward_to_deserialize_any! {
+        str string seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'a> EventRecord<'a> {
+    /// Deserialize the event into `T`. See `EventDeserializer` for how
+    /// values are mapped.
+    pub fn deserialize<T: Deserialize<'a>>(&self) -> Result<T, DeserializeError> {
+        T::deserialize(EventDeserializer::new(self))
+    }
+}
+
+impl<'a> Cursor<'a> {
+    /// Deserialize the remaining events of the current trail into `T`.
+    ///
+    /// ```no_run
+    /// # extern crate traildb;
+    /// # #[macro_use] extern crate serde_derive;
+    /// # use traildb::Db;
+    /// #[derive(Deserialize)]
+    /// struct PageView {
+    ///     time: u64,
+    ///     url: String,
+    ///     duration: Option<u32>,
+    /// }
+    ///
+    /// # fn main() {
+    /// let db = Db::open("pageviews.tdb").unwrap();
+    /// let mut cursor = db.cursor();
+    /// cursor.get_trail(0).unwrap();
+    /// for view in cursor.deserialize::<PageView>() {
+    ///     let view = view.unwrap();
+    ///     println!("{} {}", view.time, view.url);
+    /// }
+    /// # }
+    /// ```
+    pub fn deserialize<T: Deserialize<'a>>(&mut self) -> DeserializeEvents<'_, 'a, T> {
+        DeserializeEvents {
+            cursor: self,
+            _record: PhantomData,
+        }
+    }
+}
+
+/// An iterator deserializing the events of a cursor, created by
+/// `Cursor::deserialize`.
+pub struct DeserializeEvents<'c, 'a: 'c, T> {
+    cursor: &'c mut Cursor<'a>,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<'c, 'a, T: Deserialize<'a>> Iterator for DeserializeEvents<'c, 'a, T> {
+    type Item = Result<T, DeserializeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let db = self.cursor.db;
+        let event = self.cursor.next()?;
+        Some(event.decode(db)
+                  .map_err(de::Error::custom)
+                  .and_then(|record| record.deserialize()))
+    }
+}
@@ -0,0 +1,280 @@
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use traildb_sys;
+
+/// The kind of an `Error`. Each kind corresponds to a `tdb_error` code
+/// of the TrailDB C library.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ErrorKind {
+    Nomem,
+    PathTooLong,
+    UnknownField,
+    UnknownUuid,
+    InvalidTrailId,
+    HandleIsNull,
+    HandleAlreadyOpened,
+    UnknownOption,
+    InvalidOptionValue,
+    InvalidUuid,
+    IoOpen,
+    IoClose,
+    IoWrite,
+    IoRead,
+    IoTruncate,
+    IoPackage,
+    InvalidInfoFile,
+    InvalidVersionFile,
+    IncompatibleVersion,
+    InvalidFieldsFile,
+    InvalidUuidsFile,
+    InvalidCodebookFile,
+    InvalidTrailsFile,
+    InvalidLexiconFile,
+    InvalidPackage,
+    TooManyFields,
+    DuplicateFields,
+    InvalidFieldname,
+    TooManyTrails,
+    ValueTooLong,
+    AppendFieldsMismatch,
+    LexiconTooLarge,
+    TimestampTooLarge,
+    TrailTooLong,
+    OnlyDiffFilter,
+    NoSuchItem,
+    InvalidRange,
+    IncorrectTermType,
+    /// An error code this version of the bindings does not know about.
+    Unknown(i32),
+}
+
+impl ErrorKind {
+    /// Map a `tdb_error` code to its kind.
+    pub fn from_code(code: i32) -> ErrorKind {
+        match code {
+            -2 => ErrorKind::Nomem,
+            -3 => ErrorKind::PathTooLong,
+            -4 => ErrorKind::UnknownField,
+            -5 => ErrorKind::UnknownUuid,
+            -6 => ErrorKind::InvalidTrailId,
+            -7 => ErrorKind::HandleIsNull,
+            -8 => ErrorKind::HandleAlreadyOpened,
+            -9 => ErrorKind::UnknownOption,
+            -10 => ErrorKind::InvalidOptionValue,
+            -11 => ErrorKind::InvalidUuid,
+            -65 => ErrorKind::IoOpen,
+            -66 => ErrorKind::IoClose,
+            -67 => ErrorKind::IoWrite,
+            -68 => ErrorKind::IoRead,
+            -69 => ErrorKind::IoTruncate,
+            -70 => ErrorKind::IoPackage,
+            -129 => ErrorKind::InvalidInfoFile,
+            -130 => ErrorKind::InvalidVersionFile,
+            -131 => ErrorKind::IncompatibleVersion,
+            -132 => ErrorKind::InvalidFieldsFile,
+            -133 => ErrorKind::InvalidUuidsFile,
+            -134 => ErrorKind::InvalidCodebookFile,
+            -135 => ErrorKind::InvalidTrailsFile,
+            -136 => ErrorKind::InvalidLexiconFile,
+            -137 => ErrorKind::InvalidPackage,
+            -257 => ErrorKind::TooManyFields,
+            -258 => ErrorKind::DuplicateFields,
+            -259 => ErrorKind::InvalidFieldname,
+            -260 => ErrorKind::TooManyTrails,
+            -261 => ErrorKind::ValueTooLong,
+            -262 => ErrorKind::AppendFieldsMismatch,
+            -263 => ErrorKind::LexiconTooLarge,
+            -264 => ErrorKind::TimestampTooLarge,
+            -265 => ErrorKind::TrailTooLong,
+            -513 => ErrorKind::OnlyDiffFilter,
+            -514 => ErrorKind::NoSuchItem,
+            -515 => ErrorKind::InvalidRange,
+            -516 => ErrorKind::IncorrectTermType,
+            code => ErrorKind::Unknown(code),
+        }
+    }
+
+    /// The `tdb_error` code of this kind.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ErrorKind::Nomem => -2,
+            ErrorKind::PathTooLong => -3,
+            ErrorKind::UnknownField => -4,
+            ErrorKind::UnknownUuid => -5,
+            ErrorKind::InvalidTrailId => -6,
+            ErrorKind::HandleIsNull => -7,
+            ErrorKind::HandleAlreadyOpened => -8,
+            ErrorKind::UnknownOption => -9,
+            ErrorKind::InvalidOptionValue => -10,
+            ErrorKind::InvalidUuid => -11,
+            ErrorKind::IoOpen => -65,
+            ErrorKind::IoClose => -66,
+            ErrorKind::IoWrite => -67,
+            ErrorKind::IoRead => -68,
+            ErrorKind::IoTruncate => -69,
+            ErrorKind::IoPackage => -70,
+            ErrorKind::InvalidInfoFile => -129,
+            ErrorKind::InvalidVersionFile => -130,
+            ErrorKind::IncompatibleVersion => -131,
+            ErrorKind::InvalidFieldsFile => -132,
+            ErrorKind::InvalidUuidsFile => -133,
+            ErrorKind::InvalidCodebookFile => -134,
+            ErrorKind::InvalidTrailsFile => -135,
+            ErrorKind::InvalidLexiconFile => -136,
+            ErrorKind::InvalidPackage => -137,
+            ErrorKind::TooManyFields => -257,
+            ErrorKind::DuplicateFields => -258,
+            ErrorKind::InvalidFieldname => -259,
+            ErrorKind::TooManyTrails => -260,
+            ErrorKind::ValueTooLong => -261,
+            ErrorKind::AppendFieldsMismatch => -262,
+            ErrorKind::LexiconTooLarge => -263,
+            ErrorKind::TimestampTooLarge => -264,
+            ErrorKind::TrailTooLong => -265,
+            ErrorKind::OnlyDiffFilter => -513,
+            ErrorKind::NoSuchItem => -514,
+            ErrorKind::InvalidRange => -515,
+            ErrorKind::IncorrectTermType => -516,
+            ErrorKind::Unknown(code) => code,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            ErrorKind::Nomem => "out of memory",
+            ErrorKind::PathTooLong => "path is too long",
+            ErrorKind::UnknownField => "unknown field",
+            ErrorKind::UnknownUuid => "unknown UUID",
+            ErrorKind::InvalidTrailId => "invalid trail id",
+            ErrorKind::HandleIsNull => "handle is null",
+            ErrorKind::HandleAlreadyOpened => "handle is already opened",
+            ErrorKind::UnknownOption => "unknown option",
+            ErrorKind::InvalidOptionValue => "invalid option value",
+            ErrorKind::InvalidUuid => "invalid UUID",
+            ErrorKind::IoOpen => "could not open file",
+            ErrorKind::IoClose => "could not close file",
+            ErrorKind::IoWrite => "could not write file",
+            ErrorKind::IoRead => "could not read file",
+            ErrorKind::IoTruncate => "could not truncate file",
+            ErrorKind::IoPackage => "could not read or write package",
+            ErrorKind::InvalidInfoFile => "invalid info file",
+            ErrorKind::InvalidVersionFile => "invalid version file",
+            ErrorKind::IncompatibleVersion => "incompatible TrailDB version",
+            ErrorKind::InvalidFieldsFile => "invalid fields file",
+            ErrorKind::InvalidUuidsFile => "invalid UUIDs file",
+            ErrorKind::InvalidCodebookFile => "invalid codebook file",
+            ErrorKind::InvalidTrailsFile => "invalid trails file",
+            ErrorKind::InvalidLexiconFile => "invalid lexicon file",
+            ErrorKind::InvalidPackage => "invalid package",
+            ErrorKind::TooManyFields => "too many fields",
+            ErrorKind::DuplicateFields => "duplicate field name",
+            ErrorKind::InvalidFieldname => "invalid field name",
+            ErrorKind::TooManyTrails => "too many trails",
+            ErrorKind::ValueTooLong => "value is too long",
+            ErrorKind::AppendFieldsMismatch => "fields of the appended TrailDB do not match",
+            ErrorKind::LexiconTooLarge => "lexicon is too large",
+            ErrorKind::TimestampTooLarge => "timestamp is too large",
+            ErrorKind::TrailTooLong => "trail is too long",
+            ErrorKind::OnlyDiffFilter => "event filters cannot be used with only-diff items",
+            ErrorKind::NoSuchItem => "no such item in the event filter",
+            ErrorKind::InvalidRange => "invalid time range",
+            ErrorKind::IncorrectTermType => "incorrect event filter term type",
+            ErrorKind::Unknown(_) => "unknown error",
+        }
+    }
+}
+
+/// An error returned by TrailDB.
+///
+/// Besides its `ErrorKind`, an error may carry the path or field name
+/// the failed operation was working on.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    path: Option<PathBuf>,
+    field: Option<String>,
+}
+
+impl Error {
+    /// Create an error from a `tdb_error` code.
+    pub fn from_code(code: i32) -> Error {
+        Error::from(ErrorKind::from_code(code))
+    }
+
+    /// The kind of error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The `tdb_error` code of the error.
+    pub fn code(&self) -> i32 {
+        self.kind.code()
+    }
+
+    /// The name of the error code, as given by `tdb_error_str`.
+    pub fn message(&self) -> &'static str {
+        unsafe {
+            let ptr = traildb_sys::tdb_error_str(self.code());
+            if ptr.is_null() {
+                return "Unknown error";
+            }
+            CStr::from_ptr(ptr).to_str().unwrap_or("Unknown error")
+        }
+    }
+
+    /// The path involved in the failed operation, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The field name involved in the failed operation, if any.
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    pub(crate) fn with_path<P: AsRef<Path>>(mut self, path: P) -> Error {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub(crate) fn with_field<F: Into<String>>(mut self, field: F) -> Error {
+        self.field = Some(field.into());
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            path: None,
+            field: None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.kind.description(), self.message())?;
+        if let Some(ref field) = self.field {
+            write!(f, ", field `{}`", field)?;
+        }
+        if let Some(ref path) = self.path {
+            write!(f, ", path `{}`", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+/// Convert a `tdb_error` either to either a `Ok(T)` or `Err(Error)`
+pub(crate) fn wrap_tdb_err<T>(err: traildb_sys::tdb_error, val: T) -> Result<T, Error> {
+    match err {
+        traildb_sys::tdb_error_TDB_ERR_OK => Ok(val),
+        _ => Err(Error::from_code(err)),
+    }
+}
@@ -0,0 +1,370 @@
+use std::error;
+use std::fmt;
+use std::ops;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use {Db, Error, EventFilter, Item, Timestamp};
+
+/// A filter expression that does not depend on a database.
+///
+/// An `EventFilter` is made of the items of one `Db`, so it cannot be
+/// used with another database. A `FilterExpr` refers to fields and
+/// values by name and can be bound to any `Db` with `bind`.
+///
+/// ```no_run
+/// # use traildb::{Db, FilterExpr};
+/// let expr = FilterExpr::equals("author", "Asimov")
+///     .and(FilterExpr::equals("price", "").negate());
+/// for path in &["books-2016.tdb", "books-2017.tdb"] {
+///     let db = Db::open(path).unwrap();
+///     let filter = expr.bind(&db).unwrap();
+///     // ...
+/// }
+/// ```
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FilterExpr {
+    /// The field has the value. An empty value matches empty fields.
+    Equals { field: String, value: String },
+    /// The timestamp is in `start..end`.
+    TimeRange { start: Timestamp, end: Timestamp },
+    /// The expression does not match.
+    Not(Box<FilterExpr>),
+    /// All of the expressions match. Matches everything if empty.
+    And(Vec<FilterExpr>),
+    /// Any of the expressions matches. Matches nothing if empty.
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub fn equals<F: Into<String>, V: Into<String>>(field: F, value: V) -> FilterExpr {
+        FilterExpr::Equals {
+            field: field.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn not_equals<F: Into<String>, V: Into<String>>(field: F, value: V) -> FilterExpr {
+        FilterExpr::equals(field, value).negate()
+    }
+
+    pub fn time_range(start: Timestamp, end: Timestamp) -> FilterExpr {
+        FilterExpr::TimeRange { start, end }
+    }
+
+    /// Match if both `self` and `other` match.
+    pub fn and(self, other: FilterExpr) -> FilterExpr {
+        match self {
+            FilterExpr::And(mut exprs) => {
+                exprs.push(other);
+                FilterExpr::And(exprs)
+            }
+            expr => FilterExpr::And(vec![expr, other]),
+        }
+    }
+
+    /// Match if `self` or `other` matches.
+    pub fn or(self, other: FilterExpr) -> FilterExpr {
+        match self {
+            FilterExpr::Or(mut exprs) => {
+                exprs.push(other);
+                FilterExpr::Or(exprs)
+            }
+            expr => FilterExpr::Or(vec![expr, other]),
+        }
+    }
+
+    /// Match if `self` does not match.
+    pub fn negate(self) -> FilterExpr {
+        match self {
+            FilterExpr::Not(expr) => *expr,
+            expr => FilterExpr::Not(Box::new(expr)),
+        }
+    }
+
+    /// Build an `EventFilter` for the fields and values of `db`.
+    ///
+    /// The expression is first compiled to conjunctive normal form, see
+    /// `to_cnf`. A value that is not in `db` never matches, so its
+    /// negation always matches. A field that is not in `db` is empty in
+    /// every event, like the fields a merge adds. Clauses that always
+    /// match are left out.
+    pub fn bind(&self, db: &Db) -> Result<EventFilter, FilterExprError> {
+        self.bind_with_limit(db, MAX_CNF_TERMS)
+    }
+
+    /// Like `bind`, with a custom limit on the number of terms of the
+    /// compiled filter.
+    pub fn bind_with_limit(&self, db: &Db, limit: usize) -> Result<EventFilter, FilterExprError> {
+        let mut bound = vec![];
+        for clause in self.cnf(limit)? {
+            let mut bound_terms = vec![];
+            let mut always = false;
+            for literal in clause {
+                always |= bind_term(db, literal.term, literal.negated, &mut bound_terms)?;
+            }
+            if !always {
+                bound.push(bound_terms);
+            }
+        }
+        build(bound)
+    }
+
+    /// Compile the expression to the conjunctive normal form TrailDB
+    /// evaluates: an `And` of `Or`s of `Equals` and `TimeRange` terms,
+    /// each possibly negated.
+    ///
+    /// Negations are pushed down to the terms and ORs are distributed
+    /// over ANDs. Repeated terms and clauses, clauses that contain a
+    /// term and its negation, and clauses implied by smaller ones are
+    /// removed. Distributing can make the result exponentially larger
+    /// than the expression, so compiling fails with `TooLarge` once the
+    /// result, or an OR distributed before removing repeated terms, has
+    /// more than `MAX_CNF_TERMS` terms.
+    pub fn to_cnf(&self) -> Result<FilterExpr, FilterExprError> {
+        self.to_cnf_with_limit(MAX_CNF_TERMS)
+    }
+
+    /// Like `to_cnf`, with a custom limit on the number of terms.
+    pub fn to_cnf_with_limit(&self, limit: usize) -> Result<FilterExpr, FilterExprError> {
+        let clauses = self.cnf(limit)?
+            .into_iter()
+            .map(|clause| FilterExpr::Or(clause.into_iter().map(Literal::to_expr).collect()))
+            .collect();
+        Ok(FilterExpr::And(clauses))
+    }
+
+    fn cnf(&self, limit: usize) -> Result<Vec<Vec<Literal<'_>>>, FilterExprError> {
+        let mut clauses = cnf(self, false, limit)?;
+        remove_subsumed(&mut clauses);
+        Ok(clauses)
+    }
+}
+
+/// The default limit on the number of terms of a compiled filter.
+pub const MAX_CNF_TERMS: usize = 10_000;
+
+/// An `Equals` or `TimeRange` term, possibly negated.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+struct Literal<'e> {
+    term: &'e FilterExpr,
+    negated: bool,
+}
+
+impl<'e> Literal<'e> {
+    fn to_expr(self) -> FilterExpr {
+        if self.negated {
+            FilterExpr::Not(Box::new(self.term.clone()))
+        } else {
+            self.term.clone()
+        }
+    }
+}
+
+/// Compile `expr`, negated if `negated`, to a list of clauses.
+fn cnf(expr: &FilterExpr, negated: bool, limit: usize) -> Result<Vec<Vec<Literal<'_>>>, FilterExprError> {
+    let clauses = match *expr {
+        FilterExpr::Equals { .. } | FilterExpr::TimeRange { .. } => vec![vec![Literal { term: expr, negated }]],
+        FilterExpr::Not(ref expr) => return cnf(expr, !negated, limit),
+        FilterExpr::And(ref exprs) | FilterExpr::Or(ref exprs) => {
+            let conjunction = match *expr {
+                FilterExpr::And(_) => !negated,
+                _ => negated,
+            };
+            if conjunction {
+                let mut clauses = vec![];
+                for expr in exprs {
+                    clauses.extend(cnf(expr, negated, limit)?);
+                    check_size(&clauses, limit)?;
+                }
+                clauses
+            } else {
+                // (a & b) | (c & d) = (a | c) & (a | d) & (b | c) & (b | d)
+                let mut clauses = vec![vec![]];
+                for expr in exprs {
+                    let other = cnf(expr, negated, limit)?;
+                    // Building and simplifying the product takes time
+                    // quadratic in its size, so check it before removing
+                    // repeated terms
+                    let size = clauses.len().checked_mul(num_terms(&other))
+                        .and_then(|size| size.checked_add(other.len().checked_mul(num_terms(&clauses))?));
+                    if !matches!(size, Some(size) if size <= limit) {
+                        return Err(FilterExprError::TooLarge { limit });
+                    }
+                    let mut product = Vec::with_capacity(clauses.len() * other.len());
+                    for clause in &clauses {
+                        for other_clause in &other {
+                            let mut merged: Vec<Literal> = Vec::with_capacity(clause.len() + other_clause.len());
+                            merged.extend(clause);
+                            merged.extend(other_clause);
+                            product.push(merged);
+                        }
+                    }
+                    clauses = simplify(product);
+                    check_size(&clauses, limit)?;
+                }
+                clauses
+            }
+        }
+    };
+    Ok(simplify(clauses))
+}
+
+/// Remove repeated terms and clauses, and clauses that always match.
+fn simplify(clauses: Vec<Vec<Literal<'_>>>) -> Vec<Vec<Literal<'_>>> {
+    let mut simplified: Vec<Vec<Literal>> = Vec::with_capacity(clauses.len());
+    for clause in clauses {
+        let mut terms: Vec<Literal> = Vec::with_capacity(clause.len());
+        for literal in clause {
+            if !terms.contains(&literal) {
+                terms.push(literal);
+            }
+        }
+        let tautology = terms.iter()
+            .any(|a| terms.iter().any(|b| a.term == b.term && a.negated != b.negated));
+        if !tautology && !simplified.iter().any(|c| same_terms(c, &terms)) {
+            simplified.push(terms);
+        }
+    }
+    simplified
+}
+
+/// Remove clauses that contain all terms of another clause, since they
+/// match whenever the smaller clause does.
+fn remove_subsumed(clauses: &mut Vec<Vec<Literal<'_>>>) {
+    let mut i = 0;
+    while i < clauses.len() {
+        let subsumed = clauses.iter()
+            .enumerate()
+            .any(|(j, c)| j != i && c.len() < clauses[i].len() && c.iter().all(|l| clauses[i].contains(l)));
+        if subsumed {
+            clauses.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn same_terms(a: &[Literal<'_>], b: &[Literal<'_>]) -> bool {
+    a.len() == b.len() && a.iter().all(|l| b.contains(l))
+}
+
+fn num_terms(clauses: &[Vec<Literal<'_>>]) -> usize {
+    clauses.iter().map(Vec::len).sum()
+}
+
+fn check_size(clauses: &[Vec<Literal<'_>>], limit: usize) -> Result<(), FilterExprError> {
+    if num_terms(clauses) > limit {
+        return Err(FilterExprError::TooLarge { limit });
+    }
+    Ok(())
+}
+
+impl ops::Not for FilterExpr {
+    type Output = FilterExpr;
+
+    fn not(self) -> FilterExpr {
+        self.negate()
+    }
+}
+
+/// A term bound to the items of a `Db`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub(crate) enum BoundTerm {
+    Item(Item, bool),
+    TimeRange(Timestamp, Timestamp),
+}
+
+/// Bind a term and add it to `terms`. Returns true if the term always
+/// matches.
+fn bind_term(db: &Db, expr: &FilterExpr, negated: bool, terms: &mut Vec<BoundTerm>) -> Result<bool, FilterExprError> {
+    match *expr {
+        FilterExpr::Not(ref expr) => bind_term(db, expr, !negated, terms),
+        FilterExpr::Equals { ref field, ref value } => {
+            let field = match db.get_field(field) {
+                Ok(field) => field,
+                // A missing field is empty in every event, as in a merge
+                Err(_) => return Ok(value.is_empty() != negated),
+            };
+            match db.get_item(field, value) {
+                Some(item) => terms.push(BoundTerm::Item(item, negated)),
+                None if negated => return Ok(true),
+                None => {}
+            }
+            Ok(false)
+        }
+        FilterExpr::TimeRange { start, end } => {
+            if start >= end {
+                return Err(FilterExprError::InvalidRange { start, end });
+            }
+            if !negated {
+                terms.push(BoundTerm::TimeRange(start, end));
+            } else {
+                if start > 0 {
+                    terms.push(BoundTerm::TimeRange(0, start));
+                }
+                if end < Timestamp::MAX {
+                    terms.push(BoundTerm::TimeRange(end, Timestamp::MAX));
+                }
+            }
+            Ok(false)
+        }
+        FilterExpr::And(_) | FilterExpr::Or(_) => unreachable!("compiled filters only have terms"),
+    }
+}
+
+/// Build a filter from bound clauses. No clauses at all matches every
+/// event.
+pub(crate) fn build(clauses: Vec<Vec<BoundTerm>>) -> Result<EventFilter, FilterExprError> {
+    if clauses.is_empty() {
+        return Ok(EventFilter::all());
+    }
+    let mut filter = EventFilter::new();
+    for (i, clause) in clauses.into_iter().enumerate() {
+        if i > 0 {
+            filter.try_and()?;
+        }
+        for term in clause {
+            match term {
+                BoundTerm::Item(item, false) => filter.try_or(item)?,
+                BoundTerm::Item(item, true) => filter.try_or_not(item)?,
+                BoundTerm::TimeRange(start, end) => filter.try_time_range(start, end)?,
+            };
+        }
+    }
+    Ok(filter)
+}
+
+/// An error from binding a `FilterExpr` to a `Db`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum FilterExprError {
+    /// The compiled filter would have more than `limit` terms.
+    TooLarge { limit: usize },
+    /// A time range is empty.
+    InvalidRange { start: Timestamp, end: Timestamp },
+    /// TrailDB failed to build the filter.
+    Tdb(Error),
+}
+
+impl From<Error> for FilterExprError {
+    fn from(err: Error) -> FilterExprError {
+        FilterExprError::Tdb(err)
+    }
+}
+
+impl fmt::Display for FilterExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilterExprError::TooLarge { limit } => {
+                write!(f, "filter expression has more than {} terms in conjunctive normal form", limit)
+            }
+            FilterExprError::InvalidRange { start, end } => write!(f, "time range {}..{} is empty", start, end),
+            FilterExprError::Tdb(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for FilterExprError {}
@@ -0,0 +1,366 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use {Db, Error, ErrorKind, EventFilter, Field, Matches, Term, TrailId, Trails};
+
+/// The number of pages the trails of a database are split into.
+const NUM_PAGES: u64 = u16::MAX as u64 - 1;
+/// Identifies an index file.
+const MAGIC: &[u8; 8] = b"TDBINDEX";
+/// The version of the index file format.
+const VERSION: u64 = 1;
+
+/// An error from building, saving or loading an `Index`.
+#[derive(Debug)]
+pub enum IndexError {
+    /// Reading or writing the index file failed.
+    Io(io::Error),
+    /// The index was built from a different database.
+    Mismatch,
+    /// The index file is not a valid index.
+    Corrupt,
+}
+
+impl From<io::Error> for IndexError {
+    fn from(err: io::Error) -> IndexError {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            IndexError::Corrupt
+        } else {
+            IndexError::Io(err)
+        }
+    }
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexError::Io(ref err) => write!(f, "index I/O failed: {}", err),
+            IndexError::Mismatch => write!(f, "the index does not match the database"),
+            IndexError::Corrupt => write!(f, "corrupted index"),
+        }
+    }
+}
+
+impl error::Error for IndexError {}
+
+/// An index of the trails each item occurs in, to skip the trails an
+/// `EventFilter` cannot match. This is a port of the index of the `tdb`
+/// command line tool, although the file formats differ.
+///
+/// To stay small, the index does not point at trails but at pages of
+/// consecutive trails: the trails are split into at most 65534 pages.
+/// The trails of a page are all candidates when any of them has the
+/// item, so some candidates do not match the filter.
+///
+/// ```no_run
+/// # use traildb::{Db, EventFilter, Index};
+/// # use std::sync::Arc;
+/// let mut db = Db::open("events").unwrap();
+/// let index = Index::build(&db);
+/// index.save(Index::default_path("events")).unwrap();
+/// db.set_index(Some(Arc::new(index))).unwrap();
+///
+/// let filter = EventFilter::parse(&db, "action=refund").unwrap();
+/// let mut trails = db.filtered_trails(&filter).unwrap();
+/// while let Some((id, cursor)) = trails.next_trail() {
+///     println!("trail {} has {} refunds", id, cursor.len());
+/// }
+/// ```
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Index {
+    /// Describes the database the index was built from.
+    checksum: [u64; 6],
+    trails_per_page: u64,
+    /// The sorted pages of every value of every field but the timestamp.
+    pages: Vec<Vec<Vec<u16>>>,
+}
+
+impl Index {
+    /// Index every trail of `db`. Event filters set on `db` are ignored.
+    pub fn build(db: &Db) -> Index {
+        let mut index = Index::empty(db);
+        let num_pages = index.num_pages(db);
+        index.add_pages(db, 0..num_pages);
+        index
+    }
+
+    /// Index every trail of `db` in parallel on the rayon thread pool.
+    #[cfg(feature = "rayon")]
+    pub fn par_build(db: &Db) -> Index {
+        let mut index = Index::empty(db);
+        let num_pages = index.num_pages(db);
+        let chunk_size = cmp::max(1, num_pages / (rayon::current_num_threads() as u64 * 4));
+        let chunks: Vec<Index> = (0..num_pages.div_ceil(chunk_size))
+            .into_par_iter()
+            .map(|chunk| {
+                let mut part = Index::empty(db);
+                part.add_pages(db, chunk * chunk_size..cmp::min((chunk + 1) * chunk_size, num_pages));
+                part
+            })
+            .collect();
+        // The chunks are in page order, so the pages stay sorted
+        for chunk in chunks {
+            for (values, chunk_values) in index.pages.iter_mut().zip(chunk.pages) {
+                for (pages, chunk_pages) in values.iter_mut().zip(chunk_values) {
+                    pages.extend(chunk_pages);
+                }
+            }
+        }
+        index
+    }
+
+    fn empty(db: &Db) -> Index {
+        let num_trails = db.num_trails();
+        Index {
+            checksum: checksum(db),
+            trails_per_page: 1 + num_trails / NUM_PAGES,
+            pages: (1..db.num_fields() as Field).map(|field| vec![vec![]; db.lexicon_size(field) as usize]).collect(),
+        }
+    }
+
+    fn num_pages(&self, db: &Db) -> u64 {
+        db.num_trails().div_ceil(self.trails_per_page)
+    }
+
+    /// Add the items of the trails in `pages` to the index.
+    fn add_pages(&mut self, db: &Db, pages: Range<u64>) {
+        let all = EventFilter::all();
+        let mut cursor = db.cursor();
+        // `all` outlives the cursor
+        if let Err(err) = unsafe { cursor.set_filter_unchecked(&all) } {
+            // Only-diff items rule out all filters, so none needs to be
+            // overridden, and every item still shows up where it changes
+            debug_assert_eq!(ErrorKind::OnlyDiffFilter, err.kind());
+        }
+        let end = cmp::min(pages.end * self.trails_per_page, db.num_trails());
+        for id in pages.start * self.trails_per_page..end {
+            let page = (id / self.trails_per_page) as u16;
+            cursor.get_trail(id).expect("trail id out of range");
+            while let Some(event) = cursor.next() {
+                for item in event.items {
+                    let pages = &mut self.pages[item.field() as usize - 1][item.value() as usize];
+                    if pages.last() != Some(&page) {
+                        pages.push(page);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the index was built from `db`, judging by its size and
+    /// timestamps.
+    pub fn matches(&self, db: &Db) -> bool {
+        self.checksum == checksum(db)
+    }
+
+    /// The trails that may have events matching `filter`, in order.
+    ///
+    /// Clauses with negated items or time ranges cannot be checked
+    /// against the index and match every trail.
+    pub fn candidates(&self, filter: &EventFilter) -> Vec<TrailId> {
+        let num_trails = self.checksum[0];
+        let mut conjunction = match filter.matches {
+            Matches::None => return vec![],
+            Matches::All => return (0..num_trails).collect(),
+            Matches::Terms => vec![true; NUM_PAGES as usize],
+        };
+        for clause in filter.clauses() {
+            let mut disjunction = vec![false; NUM_PAGES as usize];
+            for term in clause.terms {
+                match term {
+                    Term::Item { item, negated: false } => {
+                        let pages = self.pages
+                            .get((item.field() as usize).wrapping_sub(1))
+                            .and_then(|values| values.get(item.value() as usize));
+                        for &page in pages.into_iter().flatten() {
+                            disjunction[page as usize] = true;
+                        }
+                    }
+                    _ => {
+                        disjunction = vec![true; NUM_PAGES as usize];
+                        break;
+                    }
+                }
+            }
+            for (page, &matches) in conjunction.iter_mut().zip(&disjunction) {
+                *page &= matches;
+            }
+        }
+        let trails_per_page = self.trails_per_page;
+        conjunction.iter()
+            .enumerate()
+            .filter(|&(_, &matches)| matches)
+            .flat_map(|(page, _)| page as u64 * trails_per_page..(page as u64 + 1) * trails_per_page)
+            .take_while(|&id| id < num_trails)
+            .collect()
+    }
+
+    /// The path of the index of the database at `db_path`: `index` inside
+    /// a directory database, or `<db_path>.index` next to a package.
+    pub fn default_path<P: AsRef<Path>>(db_path: P) -> PathBuf {
+        let db_path = db_path.as_ref();
+        if db_path.is_dir() {
+            db_path.join("index")
+        } else {
+            let mut path = db_path.as_os_str().to_owned();
+            path.push(".index");
+            PathBuf::from(path)
+        }
+    }
+
+    /// Look for an index of the database at `db_path` in the places
+    /// the `tdb` tool puts it: `<db_path>/index`, `<db_path>.index` and
+    /// `<db_path>.tdb.index`.
+    pub fn find<P: AsRef<Path>>(db_path: P) -> Option<PathBuf> {
+        let db_path = db_path.as_ref().as_os_str();
+        ["/index", ".index", ".tdb.index"].iter()
+            .map(|suffix| {
+                let mut path = db_path.to_owned();
+                path.push(suffix);
+                PathBuf::from(path)
+            })
+            .find(|path| path.is_file())
+    }
+
+    /// Write the index to `path`. The file is replaced atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IndexError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(MAGIC)?;
+        write_u64(&mut out, VERSION)?;
+        for &value in &self.checksum {
+            write_u64(&mut out, value)?;
+        }
+        write_u64(&mut out, self.trails_per_page)?;
+        for values in &self.pages {
+            write_u64(&mut out, values.len() as u64)?;
+            for pages in values {
+                write_u64(&mut out, pages.len() as u64)?;
+                for &page in pages {
+                    out.write_all(&page.to_le_bytes())?;
+                }
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read the index of `db` from `path`. Fails with
+    /// `IndexError::Mismatch` if it was built from another database.
+    pub fn load<P: AsRef<Path>>(db: &Db, path: P) -> Result<Index, IndexError> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u64(&mut input)? != VERSION {
+            return Err(IndexError::Corrupt);
+        }
+        let mut index = Index::empty(db);
+        let mut checksum = [0; 6];
+        for value in checksum.iter_mut() {
+            *value = read_u64(&mut input)?;
+        }
+        if checksum != index.checksum {
+            return Err(IndexError::Mismatch);
+        }
+        if read_u64(&mut input)? != index.trails_per_page {
+            return Err(IndexError::Corrupt);
+        }
+        let num_pages = index.num_pages(db);
+        for values in &mut index.pages {
+            if read_u64(&mut input)? != values.len() as u64 {
+                return Err(IndexError::Corrupt);
+            }
+            for pages in values {
+                let len = read_u64(&mut input)?;
+                if len > num_pages {
+                    return Err(IndexError::Corrupt);
+                }
+                let mut buf = vec![0; len as usize * 2];
+                input.read_exact(&mut buf)?;
+                *pages = buf.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+                if pages.iter().any(|&page| u64::from(page) >= num_pages) {
+                    return Err(IndexError::Corrupt);
+                }
+            }
+        }
+        if input.read(&mut [0])? != 0 {
+            return Err(IndexError::Corrupt);
+        }
+        Ok(index)
+    }
+}
+
+impl Db {
+    /// Use `index` to skip trails in `filtered_trails`. Fails with
+    /// `IndexError::Mismatch` if it was built from another database.
+    /// `None` removes the index.
+    pub fn set_index(&mut self, index: Option<Arc<Index>>) -> Result<(), IndexError> {
+        if let Some(ref index) = index {
+            if !index.matches(self) {
+                return Err(IndexError::Mismatch);
+            }
+        }
+        self.index = index;
+        Ok(())
+    }
+
+    /// The index set with `set_index`, if any.
+    pub fn index(&self) -> Option<&Arc<Index>> {
+        self.index.as_ref()
+    }
+
+    /// Visit the trails that may have events matching `filter`, with a
+    /// cursor that only returns those events.
+    ///
+    /// Without an index every trail is visited. With one, only the
+    /// candidates of `Index::candidates` are, some of which may have no
+    /// matching events.
+    ///
+    /// The event filters of the database still apply: the cursor only
+    /// returns events that also match the trail filter of their trail,
+    /// or the db-wide filter for trails without one. Fails with
+    /// `ErrorKind::OnlyDiffFilter` if `DbOption::OnlyDiffItems` is set.
+    pub fn filtered_trails(&self, filter: &EventFilter) -> Result<Trails<'_, vec::IntoIter<TrailId>>, Error> {
+        if self.only_diff_items()? {
+            return Err(ErrorKind::OnlyDiffFilter.into());
+        }
+        let filters = self.cursor_filters(|db_filter| db_filter.and_filter(filter))?;
+        let ids = match self.index {
+            Some(ref index) => index.candidates(filter),
+            None => (0..self.num_trails()).collect(),
+        };
+        let mut trails = Trails::new(self, ids.into_iter());
+        trails.filters = Some(filters);
+        Ok(trails)
+    }
+}
+
+/// The properties of `db` an index must agree with.
+fn checksum(db: &Db) -> [u64; 6] {
+    [db.num_trails(), db.num_events(), db.num_fields(), db.min_timestamp(), db.max_timestamp(), db.version()]
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
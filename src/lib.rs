@@ -1,105 +1,49 @@
-#[allow(non_camel_case_types,dead_code,non_snake_case)]
 extern crate traildb_sys;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
 use std::path::Path;
 use std::ffi::CString;
+use std::str::Utf8Error;
+use std::marker::PhantomData;
 use std::fmt;
-use std::mem::{transmute, forget};
-use std::cell::RefCell;
-
-use std::collections::HashMap;
-
-#[derive(Debug)]
-#[derive(PartialEq)]
-#[repr(C)]
-pub enum Error {
-    Nomem = -2,
-    PathTooLong = -3,
-    UnknownField = -4,
-    UnknownUuid = -5,
-    InvalidTrailId = -6,
-    HandleIsNull = -7,
-    HandleAlreadyOpened = -8,
-    UnknownOption = -9,
-    InvalidOptionValue = -10,
-    InvalidUuid = -11,
-    IoOpen = -65,
-    IoClose = -66,
-    IoWrite = -67,
-    IoRead = -68,
-    IoTruncate = -69,
-    IoPackage = -70,
-    InvalidInfoFile = -129,
-    InvalidVersionFile = -130,
-    IncompatibleVersion = -131,
-    InvalidFieldsFile = -132,
-    InvalidUuidsFile = -133,
-    InvalidCodebookFile = -134,
-    InvalidTrailsFile = -135,
-    InvalidLexiconFile = -136,
-    InvalidPackage = -137,
-    TooManyFields = -257,
-    DuplicateFields = -258,
-    InvalidFieldname = -259,
-    TooManyTrails = -260,
-    ValueTooLong = -261,
-    AppendFieldsMismatch = -262,
-    LexiconTooLarge = -263,
-    TimestampTooLarge = -264,
-    TrailTooLong = -265,
-    OnlyDiffFilter = -513,
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = match *self {
-            Error::Nomem => "Nomem",
-            Error::PathTooLong => "PathTooLong",
-            Error::UnknownField => "UnknownField",
-            Error::UnknownUuid => "UnknownUuid",
-            Error::InvalidTrailId => "InvalidTrailId",
-            Error::HandleIsNull => "HandleIsNull",
-            Error::HandleAlreadyOpened => "HandleAlreadyOpened",
-            Error::UnknownOption => "UnknownOption",
-            Error::InvalidOptionValue => "InvalidOptionValue",
-            Error::InvalidUuid => "InvalidUuid",
-            Error::IoOpen => "IoOpen",
-            Error::IoClose => "IoClose",
-            Error::IoWrite => "IoWrite",
-            Error::IoRead => "IoRead",
-            Error::IoTruncate => "IoTruncate",
-            Error::IoPackage => "IoPackage",
-            Error::InvalidInfoFile => "InvalidInfoFile",
-            Error::InvalidVersionFile => "InvalidVersionFile",
-            Error::IncompatibleVersion => "IncompatibleVersion",
-            Error::InvalidFieldsFile => "InvalidFieldsFile",
-            Error::InvalidUuidsFile => "InvalidUuidsFile",
-            Error::InvalidCodebookFile => "InvalidCodebookFile",
-            Error::InvalidTrailsFile => "InvalidTrailsFile",
-            Error::InvalidLexiconFile => "InvalidLexiconFile",
-            Error::InvalidPackage => "InvalidPackage",
-            Error::TooManyFields => "TooManyFields",
-            Error::DuplicateFields => "DuplicateFields",
-            Error::InvalidFieldname => "InvalidFieldname",
-            Error::TooManyTrails => "TooManyTrails",
-            Error::ValueTooLong => "ValueTooLong",
-            Error::AppendFieldsMismatch => "AppendFieldsMismatch",
-            Error::LexiconTooLarge => "LexiconTooLarge",
-            Error::TimestampTooLarge => "TimestampTooLarge",
-            Error::TrailTooLong => "TrailTooLong",
-            Error::OnlyDiffFilter => "OnlyDiffFilter",
-        };
-        write!(f, "Error::{}", s)
-    }
-}
-
-/// Convert a `tdb_error` either to either a `Ok(T)` or `Err(Error)`
-fn wrap_tdb_err<T>(err: traildb_sys::tdb_error, val: T) -> Result<T, Error> {
-    match err {
-        traildb_sys::tdb_error_TDB_ERR_OK => Ok(val),
-        _ => Err(unsafe { transmute(err) }),
-    }
-}
+use std::sync::{Arc, OnceLock};
+use std::path::PathBuf;
+
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::ops::{self, Range};
+use std::vec;
+
+mod error;
+mod parse;
+mod expr;
+mod shard;
+mod merge;
+mod index;
+#[cfg(feature = "rayon")]
+mod par;
+#[cfg(feature = "serde")]
+mod de;
+#[cfg(feature = "serde")]
+mod ser;
+
+pub use error::{Error, ErrorKind};
+pub use parse::FilterParseError;
+pub use expr::{FilterExpr, FilterExprError, MAX_CNF_TERMS};
+pub use shard::{ShardEvent, ShardSet, ShardTrail};
+pub use merge::{convert, merge, merge_with_options};
+pub use index::{Index, IndexError};
+#[cfg(feature = "rayon")]
+pub use par::ParTrails;
+#[cfg(feature = "serde")]
+pub use de::{DeserializeError, DeserializeEvents, EventDeserializer, TIMESTAMP_KEY};
+#[cfg(feature = "serde")]
+pub use ser::{SerializeError, TypedConstructor, UUID_KEY};
+use error::wrap_tdb_err;
 
 /// A timestamp must provided with added events.
 pub type Timestamp = u64;
@@ -113,13 +57,115 @@ pub type Uuid = [u8; 16];
 
 /// TODO: Document me
 #[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+#[repr(transparent)]
 pub struct Item(pub u64);
+
+impl Item {
+    /// The field this item belongs to. Mirrors `tdb_item_field`.
+    pub fn field(&self) -> Field {
+        if self.0 & 128 == 0 {
+            (self.0 & 127) as Field
+        } else {
+            ((self.0 & 127) | (((self.0 >> 8) & 127) << 7)) as Field
+        }
+    }
+
+    /// The value of this item within its field. Mirrors `tdb_item_val`.
+    pub fn value(&self) -> Value {
+        if self.0 & 128 == 0 {
+            (self.0 >> 8) & u64::from(u32::MAX)
+        } else {
+            self.0 >> 16
+        }
+    }
+}
 /// TODO: Document me
 pub type Value = u64;
 /// TODO: Document me
 pub type Field = u32;
 
 
+/// The on-disk format of a TrailDB.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format {
+    /// A directory with one file per section of the database.
+    Directory,
+    /// A single-file, tar-based package written to `<path>.tdb`.
+    Package,
+}
+
+/// An option that can be set on a `Constructor` before it is
+/// finalized.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ConsOption {
+    /// The format `finalize` writes the database in. TrailDB defaults
+    /// to `Format::Package` when it is built with libarchive.
+    OutputFormat(Format),
+    /// Skip bigram compression. Finalizing is faster, at the cost of a
+    /// larger database.
+    NoBigrams(bool),
+}
+
+/// A set of options to apply to a `Constructor` when it is created.
+///
+/// Options that are not set keep the TrailDB defaults.
+///
+/// # Examples
+///
+/// ```
+/// use traildb::{ConstructorOptions, Format};
+/// use std::path::Path;
+///
+/// let cons = ConstructorOptions::new()
+///     .output_format(Format::Directory)
+///     .no_bigrams(true)
+///     .open(Path::new("my_options_traildb"), &["user", "action"])
+///     .unwrap();
+/// assert_eq!(Format::Directory, cons.output_format().unwrap());
+/// assert!(cons.no_bigrams().unwrap());
+/// ```
+#[derive(Debug,Clone,Default)]
+pub struct ConstructorOptions {
+    output_format: Option<Format>,
+    no_bigrams: Option<bool>,
+}
+
+impl ConstructorOptions {
+    /// Create an empty set of options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the format `finalize` writes the database in.
+    pub fn output_format(&mut self, format: Format) -> &mut Self {
+        self.output_format = Some(format);
+        self
+    }
+
+    /// Enable or disable bigram compression.
+    pub fn no_bigrams(&mut self, no_bigrams: bool) -> &mut Self {
+        self.no_bigrams = Some(no_bigrams);
+        self
+    }
+
+    /// The options that were set, in the order they are applied.
+    pub fn options(&self) -> Vec<ConsOption> {
+        let mut opts = Vec::new();
+        if let Some(format) = self.output_format {
+            opts.push(ConsOption::OutputFormat(format));
+        }
+        if let Some(no_bigrams) = self.no_bigrams {
+            opts.push(ConsOption::NoBigrams(no_bigrams));
+        }
+        opts
+    }
+
+    /// Create a new constructor with these options applied.
+    pub fn open(&self, path: &Path, fields: &[&str]) -> Result<Constructor, Error> {
+        Constructor::with_options(path, fields, self)
+    }
+}
+
 
 /// A structure that represents a `TrailDB` constructor.
 ///
@@ -155,18 +201,25 @@ pub type Field = u32;
 /// ```
 pub struct Constructor {
     obj: *mut traildb_sys::tdb_cons,
+    path: PathBuf,
 }
 
 impl Constructor {
     /// Create a new TrailDB constructor.
+    ///
+    /// Errors about invalid or duplicate field names report the
+    /// offending field in `Error::field`.
     pub fn new(path: &Path, fields: &[&str]) -> Result<Self, Error> {
-
-        let mut field_ptrs = Vec::new();
+        let mut field_cstrs = Vec::new();
         for f in fields.iter() {
-            let s = CString::new(*f).unwrap();
-            field_ptrs.push(s.as_ptr());
-            forget(s);
+            match CString::new(*f) {
+                Ok(s) => field_cstrs.push(s),
+                Err(_) => {
+                    return Err(Error::from(ErrorKind::InvalidFieldname).with_field(*f).with_path(path))
+                }
+            }
         }
+        let field_ptrs: Vec<*const i8> = field_cstrs.iter().map(|s| s.as_ptr()).collect();
 
         let ptr = unsafe { traildb_sys::tdb_cons_init() };
         let ret = unsafe {
@@ -175,14 +228,100 @@ impl Constructor {
                                        field_ptrs.as_slice().as_ptr() as *mut *const i8,
                                        field_ptrs.len() as u64)
         };
-        wrap_tdb_err(ret, Constructor { obj: ptr })
+        let cons = Constructor {
+            obj: ptr,
+            path: path.to_path_buf(),
+        };
+        wrap_tdb_err(ret, cons).map_err(|err| {
+            let field = match err.kind() {
+                ErrorKind::InvalidFieldname => fields.iter().find(|f| !is_valid_field_name(f)),
+                ErrorKind::DuplicateFields => {
+                    let mut seen = HashSet::new();
+                    fields.iter().find(|f| !seen.insert(**f))
+                }
+                _ => None,
+            };
+            match field {
+                Some(field) => err.with_field(*field).with_path(path),
+                None => err.with_path(path),
+            }
+        })
+    }
+
+    /// Create a new TrailDB constructor with the given options applied.
+    pub fn with_options(path: &Path,
+                        fields: &[&str],
+                        options: &ConstructorOptions)
+                        -> Result<Self, Error> {
+        let mut cons = Constructor::new(path, fields)?;
+        for opt in options.options() {
+            cons.set_option(opt)?;
+        }
+        Ok(cons)
+    }
+
+    /// Set an option on the constructor.
+    ///
+    /// Fails with `ErrorKind::InvalidOptionValue` when asking for
+    /// `Format::Package` from a TrailDB built without libarchive.
+    pub fn set_option(&mut self, opt: ConsOption) -> Result<(), Error> {
+        let (key, value) = match opt {
+            ConsOption::OutputFormat(Format::Directory) => {
+                (traildb_sys::tdb_opt_key_TDB_OPT_CONS_OUTPUT_FORMAT,
+                 traildb_sys::TDB_OPT_CONS_OUTPUT_FORMAT_DIR as u64)
+            }
+            ConsOption::OutputFormat(Format::Package) => {
+                (traildb_sys::tdb_opt_key_TDB_OPT_CONS_OUTPUT_FORMAT,
+                 traildb_sys::TDB_OPT_CONS_OUTPUT_FORMAT_PACKAGE as u64)
+            }
+            ConsOption::NoBigrams(no_bigrams) => {
+                (traildb_sys::tdb_opt_key_TDB_OPT_CONS_NO_BIGRAMS, no_bigrams as u64)
+            }
+        };
+        let ret = unsafe {
+            traildb_sys::tdb_cons_set_opt(self.obj, key, traildb_sys::tdb_opt_value { value })
+        };
+        wrap_tdb_err(ret, ())
+    }
+
+    /// The format `finalize` will write the database in.
+    pub fn output_format(&self) -> Result<Format, Error> {
+        let value = self.get_opt(traildb_sys::tdb_opt_key_TDB_OPT_CONS_OUTPUT_FORMAT)?;
+        match value {
+            v if v == traildb_sys::TDB_OPT_CONS_OUTPUT_FORMAT_DIR as u64 => Ok(Format::Directory),
+            v if v == traildb_sys::TDB_OPT_CONS_OUTPUT_FORMAT_PACKAGE as u64 => Ok(Format::Package),
+            _ => Err(ErrorKind::InvalidOptionValue.into()),
+        }
+    }
+
+    /// Whether bigram compression is disabled.
+    pub fn no_bigrams(&self) -> Result<bool, Error> {
+        let value = self.get_opt(traildb_sys::tdb_opt_key_TDB_OPT_CONS_NO_BIGRAMS)?;
+        Ok(value != 0)
+    }
+
+    fn get_opt(&self, key: traildb_sys::tdb_opt_key) -> Result<u64, Error> {
+        let mut value = traildb_sys::tdb_opt_value { value: 0 };
+        let ret = unsafe { traildb_sys::tdb_cons_get_opt(self.obj, key, &mut value) };
+        wrap_tdb_err(ret, unsafe { value.value })
     }
 
     /// Add an event to the constructor.
     pub fn add(&mut self, uuid: &Uuid, timestamp: Timestamp, values: &[&str]) -> Result<(), Error> {
+        self.add_values(uuid, timestamp, values)
+    }
+
+    /// Add an event with arbitrary, not necessarily UTF-8, values to the
+    /// constructor.
+    pub fn add_bytes(&mut self, uuid: &Uuid, timestamp: Timestamp, values: &[&[u8]]) -> Result<(), Error> {
+        self.add_values(uuid, timestamp, values)
+    }
+
+    fn add_values<V: AsRef<[u8]>>(&mut self, uuid: &Uuid, timestamp: Timestamp, values: &[V]) -> Result<(), Error> {
         let mut val_ptrs = Vec::new();
         let mut val_lens = Vec::new();
         for v in values.iter() {
+            let v = v.as_ref();
             val_ptrs.push(v.as_ptr());
             val_lens.push(v.len() as u64);
         }
@@ -191,25 +330,25 @@ impl Constructor {
                                       uuid.as_ptr() as *mut u8,
                                       timestamp,
                                       val_ptrs.as_slice().as_ptr() as *mut *const i8,
-                                      val_lens.as_slice().as_ptr() as *const u64)
+                                      val_lens.as_slice().as_ptr())
         };
         wrap_tdb_err(ret, ())
     }
 
     /// Close a constructor without writing it to disk.
-    pub fn close(&mut self) {
-        unsafe { traildb_sys::tdb_cons_close(self.obj) };
+    pub fn close(self) {
+        // The handle is closed when the constructor is dropped
     }
 
     /// Write the TrailDB to disk and close it.
     pub fn finalize(&mut self) -> Result<(), Error> {
         let ret = unsafe { traildb_sys::tdb_cons_finalize(self.obj) };
-        wrap_tdb_err(ret, ())
+        wrap_tdb_err(ret, ()).map_err(|err| err.with_path(&self.path))
     }
 
     /// Combine an already finalized TrailDB with a constructor.
     pub fn append(&mut self, db: &Db) -> Result<(), Error> {
-        let ret = unsafe { traildb_sys::tdb_cons_append(self.obj, transmute(db)) };
+        let ret = unsafe { traildb_sys::tdb_cons_append(self.obj, db.obj) };
         wrap_tdb_err(ret, ())
     }
 }
@@ -220,22 +359,177 @@ impl Drop for Constructor {
     }
 }
 
+// A constructor handle is not tied to the thread that created it.
+unsafe impl Send for Constructor {}
+
+
+/// An option that can be set on a `Db`. Options apply to every cursor
+/// created from the database afterwards.
+#[derive(Clone)]
+pub enum DbOption {
+    /// Only return the items that changed since the previous event of
+    /// a trail. Cannot be combined with event filters.
+    OnlyDiffItems(bool),
+    /// A filter applied to all trails, turning the database into a
+    /// pre-filtered view. The `Db` keeps the filter alive for as long as
+    /// it is set. `None` removes the filter.
+    EventFilter(Option<Arc<EventFilter>>),
+    /// The number of events a cursor decodes at once. Must be greater
+    /// than zero.
+    CursorEventBufferSize(u64),
+}
 
-pub struct Db<'a> {
-    obj: &'a mut traildb_sys::tdb,
+/// A finalized, read-only TrailDB.
+///
+/// A `Db` is `Send` and `Sync`: open it once, share it with `Arc` and
+/// create a `Cursor` per thread to read trails in parallel.
+pub struct Db {
+    obj: *mut traildb_sys::tdb,
+    event_filter: Option<Arc<EventFilter>>,
+    trail_filters: HashMap<TrailId, Arc<EventFilter>>,
+    trail_times: OnceLock<Vec<(Timestamp, Timestamp)>>,
+    index: Option<Arc<Index>>,
+    format: Format,
 }
 
-impl<'a> Db<'a> {
+impl Db {
+    /// Open the TrailDB at `path`: a directory, a package file, or a
+    /// package at `<path>.tdb`. A corrupted package fails with
+    /// `ErrorKind::InvalidPackage`.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let ptr = unsafe { traildb_sys::tdb_init() };
-        let ret = unsafe { traildb_sys::tdb_open(ptr, path_cstr(path).as_ptr()) };
-        unsafe { wrap_tdb_err(ret, Db { obj: transmute(ptr) }) }
+        let ret = unsafe { traildb_sys::tdb_open(ptr, path_cstr(&path).as_ptr()) };
+        // TrailDB opens directories as such and any other file as a package
+        let format = if path.as_ref().is_dir() {
+            Format::Directory
+        } else {
+            Format::Package
+        };
+        let db = Db {
+            obj: ptr,
+            event_filter: None,
+            trail_filters: HashMap::new(),
+            trail_times: OnceLock::new(),
+            index: None,
+            format,
+        };
+        wrap_tdb_err(ret, db).map_err(|err| err.with_path(path))
     }
 
-    pub fn close(&mut self) {
-        unsafe {
-            traildb_sys::tdb_close(self.obj);
+    /// The format the database was opened from.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Set an option on the database.
+    ///
+    /// Fails with `ErrorKind::OnlyDiffFilter` when combining
+    /// `DbOption::OnlyDiffItems` with an event filter.
+    pub fn set_option(&mut self, opt: DbOption) -> Result<(), Error> {
+        match opt {
+            DbOption::OnlyDiffItems(only_diff) => {
+                if only_diff && (self.event_filter.is_some() || !self.trail_filters.is_empty()) {
+                    return Err(ErrorKind::OnlyDiffFilter.into());
+                }
+                self.set_opt(traildb_sys::tdb_opt_key_TDB_OPT_ONLY_DIFF_ITEMS,
+                             traildb_sys::tdb_opt_value { value: only_diff as u64 })
+            }
+            DbOption::EventFilter(filter) => {
+                let ptr = match filter {
+                    Some(ref f) => {
+                        if self.only_diff_items()? {
+                            return Err(ErrorKind::OnlyDiffFilter.into());
+                        }
+                        f.obj as *const std::os::raw::c_void
+                    }
+                    None => std::ptr::null(),
+                };
+                self.set_opt(traildb_sys::tdb_opt_key_TDB_OPT_EVENT_FILTER,
+                             traildb_sys::tdb_opt_value { ptr })?;
+                self.event_filter = filter;
+                Ok(())
+            }
+            DbOption::CursorEventBufferSize(size) => {
+                self.set_opt(traildb_sys::tdb_opt_key_TDB_OPT_CURSOR_EVENT_BUFFER_SIZE,
+                             traildb_sys::tdb_opt_value { value: size })
+            }
+        }
+    }
+
+    /// Whether cursors only return the items that changed since the
+    /// previous event.
+    pub fn only_diff_items(&self) -> Result<bool, Error> {
+        let value = self.get_opt(traildb_sys::tdb_opt_key_TDB_OPT_ONLY_DIFF_ITEMS)?;
+        Ok(unsafe { value.value } != 0)
+    }
+
+    /// The filter applied to all trails, if any.
+    pub fn event_filter(&self) -> Option<&Arc<EventFilter>> {
+        self.event_filter.as_ref()
+    }
+
+    /// Set a filter for a single trail, replacing the db-wide filter
+    /// for that trail. A filter set on the cursor still takes precedence.
+    ///
+    /// The same filter can be shared by many trails. The `Db` keeps it
+    /// alive until it is cleared.
+    pub fn set_trail_filter(&mut self,
+                            trail_id: TrailId,
+                            filter: Arc<EventFilter>)
+                            -> Result<(), Error> {
+        if self.only_diff_items()? {
+            return Err(ErrorKind::OnlyDiffFilter.into());
         }
+        let value = traildb_sys::tdb_opt_value { ptr: filter.obj as *const std::os::raw::c_void };
+        self.set_trail_opt(trail_id, value)?;
+        self.trail_filters.insert(trail_id, filter);
+        Ok(())
+    }
+
+    /// Remove the filter of a single trail, returning it if one was set.
+    pub fn clear_trail_filter(&mut self, trail_id: TrailId) -> Result<Option<Arc<EventFilter>>, Error> {
+        self.set_trail_opt(trail_id, traildb_sys::tdb_opt_value { ptr: std::ptr::null() })?;
+        Ok(self.trail_filters.remove(&trail_id))
+    }
+
+    /// The filter set for a single trail, if any.
+    pub fn trail_filter(&self, trail_id: TrailId) -> Option<&Arc<EventFilter>> {
+        self.trail_filters.get(&trail_id)
+    }
+
+    fn set_trail_opt(&mut self, trail_id: TrailId, value: traildb_sys::tdb_opt_value) -> Result<(), Error> {
+        let ret = unsafe {
+            traildb_sys::tdb_set_trail_opt(self.obj,
+                                           trail_id,
+                                           traildb_sys::tdb_opt_key_TDB_OPT_EVENT_FILTER,
+                                           value)
+        };
+        wrap_tdb_err(ret, ())
+    }
+
+    /// The number of events a cursor decodes at once.
+    pub fn cursor_event_buffer_size(&self) -> Result<u64, Error> {
+        let value = self.get_opt(traildb_sys::tdb_opt_key_TDB_OPT_CURSOR_EVENT_BUFFER_SIZE)?;
+        Ok(unsafe { value.value })
+    }
+
+    fn set_opt(&mut self,
+               key: traildb_sys::tdb_opt_key,
+               value: traildb_sys::tdb_opt_value)
+               -> Result<(), Error> {
+        let ret = unsafe { traildb_sys::tdb_set_opt(self.obj, key, value) };
+        wrap_tdb_err(ret, ())
+    }
+
+    fn get_opt(&self, key: traildb_sys::tdb_opt_key) -> Result<traildb_sys::tdb_opt_value, Error> {
+        let mut value = traildb_sys::tdb_opt_value { value: 0 };
+        let ret = unsafe { traildb_sys::tdb_get_opt(self.obj, key, &mut value) };
+        wrap_tdb_err(ret, value)
+    }
+
+    /// Close the database. This is the same as dropping it.
+    pub fn close(self) {
+        // The handle is closed when the db is dropped
     }
 
     pub fn num_trails(&self) -> u64 {
@@ -270,14 +564,14 @@ impl<'a> Db<'a> {
         unsafe { traildb_sys::tdb_dontneed(self.obj) };
     }
 
-    pub fn get_trail(&self, trail_id: TrailId) -> Option<Trail> {
+    pub fn get_trail(&self, trail_id: TrailId) -> Option<Trail<'_>> {
         let mut cursor = self.cursor();
         if cursor.get_trail(trail_id).is_err() {
             return None;
         };
         Some(Trail {
             id: trail_id,
-            cursor: cursor,
+            cursor,
         })
     }
 
@@ -299,34 +593,153 @@ impl<'a> Db<'a> {
         }
     }
 
-    pub fn cursor(&'a self) -> Cursor<'a> {
-        unsafe {
-            let ptr = traildb_sys::tdb_cursor_new(self.obj);
-            Cursor { obj: transmute(ptr) }
-        }
+    pub fn cursor(&self) -> Cursor<'_> {
+        let ptr = unsafe { traildb_sys::tdb_cursor_new(self.obj) };
+        Cursor { obj: ptr, db: self }
     }
 
-    pub fn iter(&'a self) -> DbIter<'a> {
+    /// Iterate over all trails, allocating a new cursor for each trail.
+    /// `trails` is faster for passes over the whole database.
+    pub fn iter(&self) -> DbIter<'_> {
         DbIter { pos: 0, db: self }
     }
 
-    pub fn get_item_value(&'a self, item: Item) -> Option<&'a str> {
+    /// Visit every trail in order with a single, reused cursor.
+    pub fn trails(&self) -> Trails<'_, Range<TrailId>> {
+        self.trails_in(0..self.num_trails())
+    }
+
+    /// Visit the trails with ids in `range` with a single, reused
+    /// cursor. Ids past the last trail are ignored.
+    pub fn trails_in(&self, range: Range<TrailId>) -> Trails<'_, Range<TrailId>> {
+        let end = cmp::min(range.end, self.num_trails());
+        Trails::new(self, range.start..end)
+    }
+
+    /// Visit the trails of the given UUIDs, in the order given, with a
+    /// single, reused cursor. UUIDs that are not in the database are
+    /// skipped.
+    pub fn trails_for_uuids(&self, uuids: &[Uuid]) -> Trails<'_, vec::IntoIter<TrailId>> {
+        let ids: Vec<TrailId> = uuids.iter().filter_map(|uuid| self.get_trail_id(uuid)).collect();
+        Trails::new(self, ids.into_iter())
+    }
+
+    /// The timestamps of the first and last event of a trail, ignoring
+    /// event filters. Returns `None` for unknown trail ids.
+    ///
+    /// The first call reads every trail of the database, later calls
+    /// use the cached timestamps.
+    pub fn trail_time_range(&self, trail_id: TrailId) -> Option<(Timestamp, Timestamp)> {
+        self.trail_times().get(trail_id as usize).cloned()
+    }
+
+    fn trail_times(&self) -> &[(Timestamp, Timestamp)] {
+        self.trail_times.get_or_init(|| {
+            let all = EventFilter::all();
+            let mut cursor = self.cursor();
+            // `all` outlives the cursor
+            if let Err(err) = unsafe { cursor.set_filter_unchecked(&all) } {
+                // With only-diff items no filter can be set, neither here
+                // nor on the db, so the cursor returns every event anyway
+                debug_assert_eq!(ErrorKind::OnlyDiffFilter, err.kind());
+            }
+            (0..self.num_trails())
+                .map(|id| {
+                    cursor.get_trail(id).expect("trail id out of range");
+                    let (mut first, mut last) = (Timestamp::MAX, 0);
+                    while let Some(event) = cursor.next() {
+                        first = cmp::min(first, event.timestamp);
+                        last = event.timestamp;
+                    }
+                    (first, last)
+                })
+                .collect()
+        })
+    }
+
+    /// Iterate over the events with timestamps in `start..end`, with
+    /// their trail ids, in trail order.
+    ///
+    /// The event filters of the database still apply. Trails without
+    /// events in the range are skipped without being decoded, using the
+    /// timestamps of `trail_time_range`. Fails with
+    /// `ErrorKind::InvalidRange` if the range is empty, and with
+    /// `ErrorKind::OnlyDiffFilter` if `DbOption::OnlyDiffItems` is set,
+    /// since the range is applied as an event filter.
+    ///
+    /// ```no_run
+    /// # use traildb::Db;
+    /// let db = Db::open("events.tdb").unwrap();
+    /// let end = db.max_timestamp() + 1;
+    /// let mut events = db.events_between(end - 3600, end).unwrap();
+    /// while let Some((id, event)) = events.next_event() {
+    ///     println!("trail {} at {}", id, event.timestamp);
+    /// }
+    /// ```
+    pub fn events_between(&self, start: Timestamp, end: Timestamp) -> Result<EventsBetween<'_>, Error> {
+        if self.only_diff_items()? {
+            return Err(ErrorKind::OnlyDiffFilter.into());
+        }
+        let filters = self.cursor_filters(|filter| filter.and_time_range(start, end))?;
+        let ids = if start > self.max_timestamp() || end <= self.min_timestamp() {
+            0..0
+        } else {
+            0..self.num_trails()
+        };
+        Ok(EventsBetween {
+            cursor: self.cursor(),
+            filters,
+            ids,
+            trail: None,
+            start,
+            end,
+        })
+    }
+
+    /// The event filters of the database, each combined with `f`.
+    fn cursor_filters<F>(&self, f: F) -> Result<CursorFilters, Error>
+        where F: Fn(&EventFilter) -> Result<EventFilter, Error>
+    {
+        let filter = match self.event_filter {
+            Some(ref filter) => f(filter)?,
+            None => f(&EventFilter::all())?,
+        };
+        let mut trail_filters = HashMap::new();
+        for (&id, filter) in &self.trail_filters {
+            trail_filters.insert(id, f(filter)?);
+        }
+        Ok(CursorFilters { filter, trail_filters })
+    }
+
+    /// Get the value of an item as a string. Returns `Ok(None)` for
+    /// empty values and unknown items, and an error if the value is not
+    /// valid UTF-8.
+    pub fn get_item_value(&self, item: Item) -> Result<Option<&str>, Utf8Error> {
+        match self.get_item_bytes(item) {
+            Some(bytes) if !bytes.is_empty() => std::str::from_utf8(bytes).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the raw value of an item. Returns `None` for unknown items.
+    pub fn get_item_bytes(&self, item: Item) -> Option<&[u8]> {
         unsafe {
             let mut len = 0u64;
-            let ptr = traildb_sys::tdb_get_item_value(self.obj, transmute(item), &mut len);
-            if len > 0 {
-                let s = std::slice::from_raw_parts(ptr as *const u8, len as usize);
-                Some(std::str::from_utf8_unchecked(s))
-            } else {
+            let ptr = traildb_sys::tdb_get_item_value(self.obj, item.0, &mut len);
+            if ptr.is_null() {
                 None
+            } else {
+                Some(std::slice::from_raw_parts(ptr as *const u8, len as usize))
             }
         }
     }
 
-    pub fn get_item(&'a self, field: Field, value: &str) -> Option<Item> {
+    /// Look up the item of a value, which may be a `&str` or raw bytes.
+    pub fn get_item<V: AsRef<[u8]>>(&self, field: Field, value: V) -> Option<Item> {
+        let value = value.as_ref();
         unsafe {
             let item = traildb_sys::tdb_get_item(self.obj,
-                                                 transmute(field),
+                                                 field,
                                                  value.as_ptr() as *const i8,
                                                  value.len() as u64);
 
@@ -334,37 +747,52 @@ impl<'a> Db<'a> {
         }
     }
 
-    pub fn get_field_name(&'a self, field: Field) -> Option<&'a str> {
+    /// Look up a field by its name.
+    pub fn get_field(&self, name: &str) -> Result<Field, Error> {
+        let unknown = || Error::from(ErrorKind::UnknownField).with_field(name);
+        let name_cstr = CString::new(name).map_err(|_| unknown())?;
+        let mut field: Field = 0;
+        let ret = unsafe { traildb_sys::tdb_get_field(self.obj, name_cstr.as_ptr(), &mut field) };
+        wrap_tdb_err(ret, field).map_err(|_| unknown())
+    }
+
+    pub fn get_field_name(&self, field: Field) -> Option<&str> {
         unsafe {
             let ptr = traildb_sys::tdb_get_field_name(self.obj, field);
-            match std::ffi::CStr::from_ptr(ptr).to_str() {
-                Ok(s) => Some(s),
-                Err(_) => None,
+            if ptr.is_null() {
+                return None;
             }
+            std::ffi::CStr::from_ptr(ptr).to_str().ok()
         }
     }
 
-    pub fn lexicon_size(&'a self, field: Field) -> u64 {
+    pub fn lexicon_size(&self, field: Field) -> u64 {
         unsafe { traildb_sys::tdb_lexicon_size(self.obj, field) }
     }
 
-    pub fn lexicon(&'a self, field: Field) -> Vec<&'a str> {
+    /// All distinct values of a field as strings. Fails if any value is
+    /// not valid UTF-8.
+    pub fn lexicon(&self, field: Field) -> Result<Vec<&str>, Utf8Error> {
+        self.lexicon_bytes(field).into_iter().map(std::str::from_utf8).collect()
+    }
+
+    /// All distinct values of a field.
+    pub fn lexicon_bytes(&self, field: Field) -> Vec<&[u8]> {
         let mut vec = Vec::with_capacity(self.lexicon_size(field) as usize);
         for i in 1..self.lexicon_size(field) {
             let value = unsafe {
                 let mut len = 0u64;
                 let ptr = traildb_sys::tdb_get_value(self.obj, field, i, &mut len);
-                let s = std::slice::from_raw_parts(ptr as *const u8, len as usize);
-                std::str::from_utf8_unchecked(s)
+                std::slice::from_raw_parts(ptr as *const u8, len as usize)
             };
             vec.push(value);
         }
         vec
     }
 
-    pub fn fields(&'a self) -> HashMap<&str, Field> {
+    pub fn fields(&self) -> HashMap<&str, Field> {
         let num_fields = self.num_fields();
-        let mut fields: HashMap<&'a str, Field> = HashMap::with_capacity(num_fields as usize);
+        let mut fields: HashMap<&str, Field> = HashMap::with_capacity(num_fields as usize);
 
         for i in 1..num_fields {
             let field: Field = i as u32;
@@ -376,18 +804,23 @@ impl<'a> Db<'a> {
 }
 
 
-impl<'a> Drop for Db<'a> {
+impl Drop for Db {
     fn drop(&mut self) {
         unsafe { traildb_sys::tdb_close(self.obj) };
     }
 }
 
+// An opened TrailDB is immutable. Options that change how it is read
+// require `&mut Db`, so shared access from many threads is safe.
+unsafe impl Send for Db {}
+unsafe impl Sync for Db {}
+
 
 
 
 pub struct DbIter<'a> {
     pos: u64,
-    db: &'a Db<'a>,
+    db: &'a Db,
 }
 
 impl<'a> Iterator for DbIter<'a> {
@@ -400,10 +833,7 @@ impl<'a> Iterator for DbIter<'a> {
         match cursor.get_trail(id) {
             Err(_) => None,
             Ok(()) => {
-                let trail = Trail {
-                    id: id,
-                    cursor: cursor,
-                };
+                let trail = Trail { id, cursor };
                 Some(trail)
             }
         }
@@ -419,30 +849,124 @@ impl<'a> Iterator for DbIter<'a> {
 /// `cursor.get_trail()` with the `TrailId`. Initializing a cursor is
 /// expensive and using `DbIter` which initializes a new cursor for
 /// each trail will be much slower, than re-using a cursor.
+///
+/// A cursor borrows its `Db` and can be moved to another thread, but
+/// not shared between threads.
 pub struct Cursor<'a> {
-    obj: &'a mut traildb_sys::tdb_cursor,
+    obj: *mut traildb_sys::tdb_cursor,
+    db: &'a Db,
 }
 
 
 
 impl<'a> Cursor<'a> {
+    /// The database this cursor reads from.
+    pub fn db(&self) -> &'a Db {
+        self.db
+    }
+
     pub fn get_trail(&mut self, trail_id: TrailId) -> Result<(), Error> {
         let ret = unsafe { traildb_sys::tdb_get_trail(self.obj, trail_id) };
         wrap_tdb_err(ret, ())
     }
 
+    /// Number of events left in the current trail. This consumes the
+    /// trail.
     pub fn len(&mut self) -> u64 {
         unsafe { traildb_sys::tdb_get_trail_length(self.obj) }
     }
 
-    pub fn set_filter(&mut self, filter: &EventFilter) -> Result<(), Error> {
-        let ret = unsafe { traildb_sys::tdb_cursor_set_event_filter(self.obj, filter.obj) };
+    /// Whether the current trail has no events left. This consumes the
+    /// trail.
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    /// Only return events matching `filter`. The filter must outlive
+    /// the cursor.
+    pub fn set_filter(&mut self, filter: &'a EventFilter) -> Result<(), Error> {
+        unsafe { self.set_filter_unchecked(filter) }
+    }
+
+    /// `set_filter` for filters that are not borrowed for `'a`. The
+    /// caller must keep `filter` alive while it is set.
+    unsafe fn set_filter_unchecked(&mut self, filter: &EventFilter) -> Result<(), Error> {
+        let ret = traildb_sys::tdb_cursor_set_event_filter(self.obj, filter.obj);
         wrap_tdb_err(ret, ())
     }
 
     pub fn unset_filter(&mut self) {
         unsafe { traildb_sys::tdb_cursor_unset_event_filter(self.obj) };
     }
+
+    /// The next event of the trail.
+    ///
+    /// The event points into a buffer that the cursor reuses, so it
+    /// borrows the cursor and must be dropped before the cursor moves
+    /// on. Use `Event::decode` to keep the values of an event.
+    ///
+    /// ```compile_fail
+    /// # use traildb::Db;
+    /// let db = Db::open("events.tdb").unwrap();
+    /// let mut cursor = db.cursor();
+    /// cursor.get_trail(0).unwrap();
+    /// let first = cursor.next();
+    /// let second = cursor.next();
+    /// println!("{:?} {:?}", first, second);
+    /// ```
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Event<'_>> {
+        unsafe {
+            let e = traildb_sys::tdb_cursor_next(self.obj);
+            Event::from_tdb_event(e)
+        }
+    }
+
+    /// The next event of the trail, without advancing the cursor. Like
+    /// the event of `next`, it borrows the cursor.
+    pub fn peek(&mut self) -> Option<Event<'_>> {
+        unsafe { Event::from_tdb_event(traildb_sys::tdb_cursor_peek(self.obj)) }
+    }
+
+    /// Skip to the first remaining event of the trail with a timestamp
+    /// of at least `timestamp`. Returns false, with the trail consumed,
+    /// if there is no such event.
+    ///
+    /// Skipped events are stepped over in the decoded buffer, without
+    /// going through `next`.
+    ///
+    /// ```no_run
+    /// # use traildb::Db;
+    /// let db = Db::open("events.tdb").unwrap();
+    /// let mut cursor = db.cursor();
+    /// cursor.get_trail(0).unwrap();
+    /// if cursor.seek_to_timestamp(1500000000) {
+    ///     while let Some(event) = cursor.next() {
+    ///         println!("{}", event.timestamp);
+    ///     }
+    /// }
+    /// ```
+    pub fn seek_to_timestamp(&mut self, timestamp: Timestamp) -> bool {
+        const EVENT_SIZE: usize = std::mem::size_of::<traildb_sys::tdb_event>();
+        const ITEM_SIZE: usize = std::mem::size_of::<traildb_sys::tdb_item>();
+        unsafe {
+            loop {
+                if (*self.obj).num_events_left == 0 && traildb_sys::_tdb_cursor_next_batch(self.obj) == 0 {
+                    return false;
+                }
+                // The same steps as `tdb_cursor_next`, for a whole batch
+                let cursor = &mut *self.obj;
+                while cursor.num_events_left > 0 {
+                    let event = &*(cursor.next_event as *const traildb_sys::tdb_event);
+                    if event.timestamp >= timestamp {
+                        return true;
+                    }
+                    cursor.next_event = cursor.next_event.add(EVENT_SIZE + event.num_items as usize * ITEM_SIZE);
+                    cursor.num_events_left -= 1;
+                }
+            }
+        }
+    }
 }
 
 impl<'a> Drop for Cursor<'a> {
@@ -451,105 +975,341 @@ impl<'a> Drop for Cursor<'a> {
     }
 }
 
-impl<'a> Iterator for Cursor<'a> {
-    type Item = Event<'a>;
+// A cursor owns its decoding state; the `Db` and filters it points to
+// are `Sync`.
+unsafe impl<'a> Send for Cursor<'a> {}
 
-    fn next(&mut self) -> Option<Event<'a>> {
-        unsafe {
-            let e = traildb_sys::tdb_cursor_next(self.obj);
-            Event::from_tdb_event(e)
-        }
-    }
-}
 
 /// A `MultiCursor` allows you to iterate over multiple cursors at the
-/// same time, even from different TrailDBs.
-pub struct MultiCursor<'a> {
-    obj: &'a mut traildb_sys::tdb_multi_cursor,
+/// same time, even from different TrailDBs. Events are returned in
+/// timestamp order.
+///
+/// The multi-cursor holds its cursors, either owned (`Cursor`) or
+/// mutably borrowed (`&mut Cursor`), so they cannot be dropped or moved
+/// to another trail behind its back. Use `MultiCursor::get_trail` to
+/// move a member cursor.
+///
+/// ```no_run
+/// # use traildb::{Db, MultiCursor};
+/// let a = Db::open("a.tdb").unwrap();
+/// let b = Db::open("b.tdb").unwrap();
+/// let mut multi = MultiCursor::new(vec![a.cursor(), b.cursor()]);
+/// multi.get_trail(0, 0).unwrap();
+/// multi.get_trail(1, 0).unwrap();
+/// while let Some(event) = multi.next() {
+///     println!("{} from cursor {}", event.event.timestamp, event.cursor_idx);
+/// }
+/// ```
+pub struct MultiCursor<'a, C = Cursor<'a>> {
+    obj: *mut traildb_sys::tdb_multi_cursor,
+    cursors: Vec<C>,
+    _db: PhantomData<&'a Db>,
 }
 
-impl<'a> MultiCursor<'a> {
-    /// Open a cursor in each of the Dbs passed. If you want multiple
-    /// cursors for the same db, include it multiple times.
-    pub fn new(cursors: &[RefCell<Cursor<'a>>]) -> MultiCursor<'a> {
-        let mut ptrs: Vec<*const traildb_sys::tdb_cursor> = vec![];
-        for refcell in cursors.iter() {
-            let cursor = refcell.borrow();
-            let ptr: *const traildb_sys::tdb_cursor = cursor.obj;
-            ptrs.push(ptr);
-        }
+mod sealed {
+    pub trait Sealed {}
+}
 
-        unsafe {
-            let ptr = traildb_sys::tdb_multi_cursor_new(ptrs.as_slice().as_ptr() as
-                                                            *mut *mut traildb_sys::tdb_cursor,
-                                                        ptrs.len() as u64);
-            MultiCursor { obj: transmute(ptr) }
+/// A cursor that can be merged by a `MultiCursor`: `Cursor` or
+/// `&mut Cursor`.
+///
+/// The multi-cursor keeps pointers to the C cursors, so it must get
+/// the same cursor every time it asks. The trait is sealed so that no
+/// other type can hand out a different one.
+pub trait MultiCursorMember<'a>: sealed::Sealed {
+    fn cursor(&self) -> &Cursor<'a>;
+    fn cursor_mut(&mut self) -> &mut Cursor<'a>;
+}
+
+impl<'a> sealed::Sealed for Cursor<'a> {}
+
+impl<'a> MultiCursorMember<'a> for Cursor<'a> {
+    fn cursor(&self) -> &Cursor<'a> {
+        self
+    }
+
+    fn cursor_mut(&mut self) -> &mut Cursor<'a> {
+        self
+    }
+}
+
+impl<'a> sealed::Sealed for &mut Cursor<'a> {}
+
+impl<'a> MultiCursorMember<'a> for &mut Cursor<'a> {
+    fn cursor(&self) -> &Cursor<'a> {
+        self
+    }
+
+    fn cursor_mut(&mut self) -> &mut Cursor<'a> {
+        self
+    }
+}
+
+impl<'a, C: MultiCursorMember<'a>> MultiCursor<'a, C> {
+    /// Merge the events of `cursors`. If you want multiple cursors for
+    /// the same db, include a cursor of it multiple times.
+    pub fn new(mut cursors: Vec<C>) -> MultiCursor<'a, C> {
+        let mut ptrs: Vec<*mut traildb_sys::tdb_cursor> =
+            cursors.iter_mut().map(|cursor| cursor.cursor_mut().obj).collect();
+        let ptr = unsafe { traildb_sys::tdb_multi_cursor_new(ptrs.as_mut_ptr(), ptrs.len() as u64) };
+        MultiCursor {
+            obj: ptr,
+            cursors,
+            _db: PhantomData,
         }
     }
 
+    /// Reinitialize the multi-cursor from the state of its cursors.
     pub fn reset(&mut self) {
         unsafe { traildb_sys::tdb_multi_cursor_reset(self.obj) };
     }
-}
 
-impl<'a> Drop for MultiCursor<'a> {
-    fn drop(&mut self) {
-        unsafe { traildb_sys::tdb_multi_cursor_free(self.obj) };
+    /// Number of cursors merged.
+    pub fn len(&self) -> usize {
+        self.cursors.len()
+    }
+
+    /// Whether there are no cursors to merge.
+    pub fn is_empty(&self) -> bool {
+        self.cursors.is_empty()
+    }
+
+    /// The cursor at `idx`, as in `MultiEvent::cursor_idx`.
+    pub fn cursor(&self, idx: usize) -> Option<&Cursor<'a>> {
+        self.cursors.get(idx).map(|cursor| cursor.cursor())
     }
-}
 
-impl<'a> Iterator for MultiCursor<'a> {
-    type Item = MultiEvent<'a>;
+    /// Move the cursor at `idx` to a trail and reset the multi-cursor.
+    ///
+    /// Panics if `idx` is out of range.
+    pub fn get_trail(&mut self, idx: usize, trail_id: TrailId) -> Result<(), Error> {
+        let ret = self.cursors[idx].cursor_mut().get_trail(trail_id);
+        self.reset();
+        ret
+    }
 
-    fn next(&mut self) -> Option<MultiEvent<'a>> {
+    /// The next event. It points into the buffer of a member cursor,
+    /// so it borrows the multi-cursor.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<MultiEvent<'_>> {
         unsafe {
             let e = traildb_sys::tdb_multi_cursor_next(self.obj);
             MultiEvent::from_tdb_multi_event(e)
         }
     }
+
+    /// The next event, without advancing the multi-cursor.
+    pub fn peek(&mut self) -> Option<MultiEvent<'_>> {
+        unsafe { MultiEvent::from_tdb_multi_event(traildb_sys::tdb_multi_cursor_peek(self.obj)) }
+    }
+
+    /// Return up to `max_events` next events at once. A batch can be
+    /// shorter when a cursor runs out of decoded events; it is only
+    /// empty once all cursors are exhausted. The events stay valid
+    /// until the multi-cursor is used again.
+    pub fn next_batch(&mut self, max_events: usize) -> Vec<MultiEvent<'_>> {
+        let mut events: Vec<traildb_sys::tdb_multi_event> = Vec::with_capacity(max_events);
+        unsafe {
+            let n = traildb_sys::tdb_multi_cursor_next_batch(self.obj, events.as_mut_ptr(), max_events as u64);
+            events.set_len(n as usize);
+        }
+        events.iter()
+            .filter_map(|e| MultiEvent::from_tdb_multi_event(e))
+            .collect()
+    }
+
+    /// Give back the cursors.
+    pub fn into_cursors(mut self) -> Vec<C> {
+        std::mem::take(&mut self.cursors)
+    }
+}
+
+impl<'a, C> Drop for MultiCursor<'a, C> {
+    fn drop(&mut self) {
+        unsafe { traildb_sys::tdb_multi_cursor_free(self.obj) };
+    }
 }
 
 
 
 
+
 pub struct Trail<'a> {
     pub id: TrailId,
     cursor: Cursor<'a>,
 }
 
-impl<'a> Iterator for Trail<'a> {
-    type Item = Event<'a>;
-
-    fn next(&mut self) -> Option<Event<'a>> {
+impl<'a> Trail<'a> {
+    /// The next event of the trail. Like `Cursor::next`, the event
+    /// borrows the trail.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Event<'_>> {
         self.cursor.next()
     }
 }
 
 
 
+/// A sequence of trails read with one cursor, created by `Db::trails`,
+/// `Db::trails_in` or `Db::trails_for_uuids`.
+///
+/// Unlike `DbIter`, the cursor is handed out by reference and moved to
+/// the next trail on every call, so there is no allocation per trail.
+///
+/// ```no_run
+/// # use traildb::Db;
+/// let db = Db::open("events.tdb").unwrap();
+/// let mut trails = db.trails();
+/// while let Some((id, cursor)) = trails.next_trail() {
+///     println!("trail {} has {} events", id, cursor.len());
+/// }
+/// ```
+pub struct Trails<'a, I> {
+    // Declared before the filters so it is dropped first.
+    cursor: Cursor<'a>,
+    filters: Option<CursorFilters>,
+    ids: I,
+}
+
+impl<'a, I: Iterator<Item = TrailId>> Trails<'a, I> {
+    fn new(db: &'a Db, ids: I) -> Self {
+        Trails {
+            cursor: db.cursor(),
+            filters: None,
+            ids,
+        }
+    }
 
-fn path_cstr<P: AsRef<Path>>(path: P) -> CString {
-    CString::new(path.as_ref().to_str().unwrap()).unwrap()
+    /// Move the cursor to the next trail. Returns the trail id and the
+    /// cursor, positioned at the first event of the trail.
+    pub fn next_trail(&mut self) -> Option<(TrailId, &mut Cursor<'a>)> {
+        let id = self.ids.next()?;
+        if let Some(ref filters) = self.filters {
+            // The filters live as long as the cursor
+            unsafe { self.cursor.set_filter_unchecked(filters.get(id)) }
+                .expect("only-diff items are rejected by filtered_trails");
+        }
+        match self.cursor.get_trail(id) {
+            Err(_) => None,
+            Ok(()) => Some((id, &mut self.cursor)),
+        }
+    }
+
+    /// Call `f` for every remaining trail.
+    pub fn for_each<F>(mut self, mut f: F)
+        where F: FnMut(TrailId, &mut Cursor<'a>)
+    {
+        while let Some((id, cursor)) = self.next_trail() {
+            f(id, cursor);
+        }
+    }
 }
 
+/// The filters a cursor uses in place of those of the database, which
+/// it overrides: one for the trails with a trail filter and one for
+/// the others.
+struct CursorFilters {
+    filter: EventFilter,
+    trail_filters: HashMap<TrailId, EventFilter>,
+}
 
+impl CursorFilters {
+    fn get(&self, id: TrailId) -> &EventFilter {
+        self.trail_filters.get(&id).unwrap_or(&self.filter)
+    }
+}
 
-#[derive(Debug)]
-pub struct Event<'a> {
-    pub timestamp: Timestamp,
-    pub items: &'a [Item],
+/// The events in a time range, created by `Db::events_between`.
+pub struct EventsBetween<'a> {
+    // Declared before the filters so it is dropped first.
+    cursor: Cursor<'a>,
+    filters: CursorFilters,
+    ids: Range<TrailId>,
+    trail: Option<TrailId>,
+    start: Timestamp,
+    end: Timestamp,
 }
 
-impl<'a> Event<'a> {
-    fn from_tdb_event(e: *const traildb_sys::tdb_event) -> Option<Self> {
-        unsafe {
-            match e.as_ref() {
+impl<'a> EventsBetween<'a> {
+    /// Move the cursor to the next trail with events in the range.
+    fn next_trail(&mut self) -> Option<TrailId> {
+        let times = self.cursor.db.trail_times();
+        let (start, end) = (self.start, self.end);
+        let id = self.ids.find(|&id| {
+            let (first, last) = times[id as usize];
+            first < end && last >= start
+        })?;
+        // The filters live as long as the cursor
+        unsafe { self.cursor.set_filter_unchecked(self.filters.get(id)) }
+            .expect("only-diff items are rejected by events_between");
+        self.cursor.get_trail(id).ok()?;
+        Some(id)
+    }
+
+    /// The next event in the range, with its trail id. The event
+    /// borrows the cursor, so it must be dropped before the next call.
+    pub fn next_event(&mut self) -> Option<(TrailId, Event<'_>)> {
+        loop {
+            match self.trail {
+                Some(id) if self.cursor.peek().is_some() => return self.cursor.next().map(|event| (id, event)),
+                _ => self.trail = Some(self.next_trail()?),
+            }
+        }
+    }
+}
+
+
+
+
+/// Mirrors the field name rules of `tdb_cons_open`.
+fn is_valid_field_name(name: &str) -> bool {
+    name != "time" && !name.is_empty() &&
+    name.len() < traildb_sys::TDB_MAX_FIELDNAME_LENGTH as usize &&
+    name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'%')
+}
+
+fn path_cstr<P: AsRef<Path>>(path: P) -> CString {
+    CString::new(path.as_ref().to_str().unwrap()).unwrap()
+}
+
+
+
+#[derive(Debug)]
+pub struct Event<'a> {
+    pub timestamp: Timestamp,
+    pub items: &'a [Item],
+}
+
+impl<'a> Event<'a> {
+    /// Look up the field names and values of this event in `db`, which
+    /// must be the database the event was read from. Fails if a value
+    /// is not valid UTF-8.
+    ///
+    /// With `DbOption::OnlyDiffItems` only the changed fields are
+    /// included.
+    pub fn decode<'b>(&self, db: &'b Db) -> Result<EventRecord<'b>, Utf8Error> {
+        let mut fields = Vec::with_capacity(self.items.len());
+        for item in self.items {
+            let field = item.field();
+            let name = db.get_field_name(field).unwrap_or("");
+            let value = std::str::from_utf8(db.get_item_bytes(*item).unwrap_or(b""))?;
+            fields.push((field, name, value));
+        }
+        Ok(EventRecord {
+            timestamp: self.timestamp,
+            fields,
+        })
+    }
+
+    fn from_tdb_event(e: *const traildb_sys::tdb_event) -> Option<Self> {
+        unsafe {
+            match e.as_ref() {
                 None => None,
                 Some(e) => {
                     let items_ptr = std::ptr::addr_of!(e.items);
                     Some(Event {
                         timestamp: e.timestamp,
-                        items: std::slice::from_raw_parts(transmute(items_ptr),
+                        items: std::slice::from_raw_parts(items_ptr as *const Item,
                                                           e.num_items as usize),
                     })
                 }
@@ -558,6 +1318,106 @@ impl<'a> Event<'a> {
     }
 }
 
+/// A field of an event, given either by name or by `Field`.
+pub trait FieldKey {
+    fn matches(&self, field: Field, name: &str) -> bool;
+}
+
+impl FieldKey for Field {
+    fn matches(&self, field: Field, _name: &str) -> bool {
+        *self == field
+    }
+}
+
+impl FieldKey for &str {
+    fn matches(&self, _field: Field, name: &str) -> bool {
+        *self == name
+    }
+}
+
+impl FieldKey for &String {
+    fn matches(&self, _field: Field, name: &str) -> bool {
+        *self == name
+    }
+}
+
+/// An event with the names and values of its fields, created by
+/// `Event::decode`. The names and values borrow the `Db`, not the
+/// cursor.
+///
+/// ```no_run
+/// # use traildb::Db;
+/// let db = Db::open("events.tdb").unwrap();
+/// let mut cursor = db.cursor();
+/// cursor.get_trail(0).unwrap();
+/// while let Some(event) = cursor.next() {
+///     let record = event.decode(&db).unwrap();
+///     println!("{} {}", record.timestamp, &record["action"]);
+/// }
+/// ```
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct EventRecord<'a> {
+    pub timestamp: Timestamp,
+    fields: Vec<(Field, &'a str, &'a str)>,
+}
+
+impl<'a> EventRecord<'a> {
+    /// The value of a field, or `None` if the event has no such field.
+    pub fn get<K: FieldKey>(&self, key: K) -> Option<&'a str> {
+        self.fields
+            .iter()
+            .find(|&&(field, name, _)| key.matches(field, name))
+            .map(|&(_, _, value)| value)
+    }
+
+    /// The `(name, value)` pairs of the event, in field order.
+    pub fn iter<'r>(&'r self) -> impl Iterator<Item = (&'a str, &'a str)> + 'r {
+        self.fields.iter().map(|&(_, name, value)| (name, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Copy the event into an `OwnedEvent` of the trail `uuid`.
+    pub fn to_owned_event(&self, uuid: &Uuid) -> OwnedEvent {
+        OwnedEvent {
+            uuid: *uuid,
+            timestamp: self.timestamp,
+            values: self.iter().map(|(name, value)| (name.to_owned(), value.to_owned())).collect(),
+        }
+    }
+}
+
+impl<'a, K: FieldKey> ops::Index<K> for EventRecord<'a> {
+    type Output = str;
+
+    /// Panics if the event has no such field.
+    fn index(&self, key: K) -> &str {
+        self.get(key).expect("no such field in event")
+    }
+}
+
+/// An event that does not borrow the `Db` or cursor it was read from.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct OwnedEvent {
+    pub uuid: Uuid,
+    pub timestamp: Timestamp,
+    /// `(name, value)` pairs in field order.
+    pub values: Vec<(String, String)>,
+}
+
+impl OwnedEvent {
+    /// The value of the field `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub struct MultiEvent<'a> {
     pub cursor_idx: usize,
@@ -568,74 +1428,332 @@ pub struct MultiEvent<'a> {
 impl<'a> MultiEvent<'a> {
     fn from_tdb_multi_event(e: *const traildb_sys::tdb_multi_event) -> Option<Self> {
         unsafe {
-            match e.as_ref() {
-                None => None,
-                Some(multi_event) => {
-                    Some(MultiEvent {
-                        event: Event::from_tdb_event(multi_event.event).unwrap(),
-                        cursor_idx: multi_event.cursor_idx as usize,
-                    })
-                }
-            }
+            e.as_ref().map(|multi_event| MultiEvent {
+                event: Event::from_tdb_event(multi_event.event).unwrap(),
+                cursor_idx: multi_event.cursor_idx as usize,
+            })
         }
     }
 }
 
 
-pub struct EventFilter<'b> {
-    obj: &'b mut traildb_sys::tdb_event_filter
+pub struct EventFilter {
+    obj: *mut traildb_sys::tdb_event_filter,
+    matches: Matches,
+}
+
+/// Whether a filter was created to match everything or nothing, which
+/// the C API does not expose.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Matches {
+    Terms,
+    All,
+    None,
+}
+
+/// A term of an `EventFilter` clause.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Term {
+    /// The event has the item, or does not have it if `negated`.
+    Item { item: Item, negated: bool },
+    /// The timestamp of the event is in `start..end`.
+    TimeRange { start: Timestamp, end: Timestamp },
+}
+
+/// A clause of an `EventFilter`: an event matches the clause if it
+/// matches any of its terms.
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Default)]
+pub struct Clause {
+    pub terms: Vec<Term>,
 }
 
-impl<'b> EventFilter<'b> {
-    pub fn new() -> EventFilter<'b> {
+impl Default for EventFilter {
+    fn default() -> EventFilter {
+        EventFilter::new()
+    }
+}
+
+impl EventFilter {
+    pub fn new() -> EventFilter {
         let filter = unsafe { traildb_sys::tdb_event_filter_new() };
-        EventFilter { obj: unsafe { transmute(filter) } }
+        EventFilter {
+            obj: filter,
+            matches: Matches::Terms,
+        }
     }
 
-    pub fn all() -> EventFilter<'b> {
+    pub fn all() -> EventFilter {
         let filter = unsafe { traildb_sys::tdb_event_filter_new_match_all() };
-        EventFilter { obj: unsafe { transmute(filter) } }
+        EventFilter {
+            obj: filter,
+            matches: Matches::All,
+        }
     }
 
-    pub fn none() -> EventFilter<'b> {
+    pub fn none() -> EventFilter {
         let filter = unsafe { traildb_sys::tdb_event_filter_new_match_none() };
-        EventFilter { obj: unsafe { transmute(filter) } }
+        EventFilter {
+            obj: filter,
+            matches: Matches::None,
+        }
+    }
+
+    /// Whether the filter was created with `EventFilter::all`.
+    pub fn is_match_all(&self) -> bool {
+        self.matches == Matches::All
     }
 
-    pub fn or(&mut self, item: Item) -> &mut EventFilter<'b> {
-        unsafe { traildb_sys::tdb_event_filter_add_term(self.obj, item.0, false as i32); };
+    /// Whether the filter was created with `EventFilter::none`.
+    pub fn is_match_none(&self) -> bool {
+        self.matches == Matches::None
+    }
+
+    /// Add `item` to the current clause.
+    ///
+    /// Errors are lost: a term that cannot be added, e.g. to a filter
+    /// from `EventFilter::all`, is dropped. Use `try_or`.
+    #[deprecated(note = "use try_or")]
+    pub fn or(&mut self, item: Item) -> &mut EventFilter {
+        let _ = self.try_or(item);
         self
     }
 
-    pub fn or_not(&mut self, item: Item) -> &mut EventFilter<'b> {
-        unsafe { traildb_sys::tdb_event_filter_add_term(self.obj, item.0, true as i32); };
+    /// Add the negation of `item` to the current clause. Errors are
+    /// lost like in `or`, use `try_or_not`.
+    #[deprecated(note = "use try_or_not")]
+    pub fn or_not(&mut self, item: Item) -> &mut EventFilter {
+        let _ = self.try_or_not(item);
         self
     }
 
-    pub fn and(&mut self) -> &mut EventFilter<'b> {
-        let ret = wrap_tdb_err(unsafe { traildb_sys::tdb_event_filter_new_clause(self.obj) }, ());
-        ret.expect("tdb_event_filter_new_clause failed");
+    /// Start a new clause. Errors are lost like in `or`, use
+    /// `try_and`.
+    #[deprecated(note = "use try_and")]
+    pub fn and(&mut self) -> &mut EventFilter {
+        let _ = self.try_and();
         self
     }
 
-    pub fn time_range(&mut self, start: u64, end: u64) -> &mut EventFilter<'b> {
-        let ret = wrap_tdb_err(unsafe { traildb_sys::tdb_event_filter_add_time_range(self.obj, start, end) }, ());
-        ret.expect("tdb_event_filter_add_time_range failed");
+    /// Add the time range `start..end` to the current clause. Errors,
+    /// such as an empty range, are lost like in `or`; use
+    /// `try_time_range`.
+    #[deprecated(note = "use try_time_range")]
+    pub fn time_range(&mut self, start: u64, end: u64) -> &mut EventFilter {
+        let _ = self.try_time_range(start, end);
         self
     }
 
-    pub fn num_clauses(&mut self) -> u64 {
+    /// Add `item` to the current clause.
+    ///
+    /// Filters from `EventFilter::all` and `EventFilter::none` ignore
+    /// their terms, so adding one fails with
+    /// `ErrorKind::IncorrectTermType`.
+    pub fn try_or(&mut self, item: Item) -> Result<&mut EventFilter, Error> {
+        self.add_term(item, false)
+    }
+
+    /// Add the negation of `item` to the current clause. Fails like
+    /// `try_or`.
+    pub fn try_or_not(&mut self, item: Item) -> Result<&mut EventFilter, Error> {
+        self.add_term(item, true)
+    }
+
+    /// Start a new clause. Fails with `ErrorKind::IncorrectTermType` on
+    /// filters from `EventFilter::all` and `EventFilter::none`.
+    pub fn try_and(&mut self) -> Result<&mut EventFilter, Error> {
+        self.check_terms()?;
+        wrap_tdb_err(unsafe { traildb_sys::tdb_event_filter_new_clause(self.obj) }, ())?;
+        Ok(self)
+    }
+
+    /// Add the time range `start..end` to the current clause.
+    ///
+    /// Fails with `ErrorKind::InvalidRange` if the range is empty and
+    /// like `try_or` on filters that ignore their terms.
+    pub fn try_time_range(&mut self, start: u64, end: u64) -> Result<&mut EventFilter, Error> {
+        self.check_terms()?;
+        if start >= end {
+            return Err(Error::from(ErrorKind::InvalidRange));
+        }
+        wrap_tdb_err(unsafe { traildb_sys::tdb_event_filter_add_time_range(self.obj, start, end) }, ())?;
+        Ok(self)
+    }
+
+    fn add_term(&mut self, item: Item, negated: bool) -> Result<&mut EventFilter, Error> {
+        self.check_terms()?;
+        wrap_tdb_err(unsafe { traildb_sys::tdb_event_filter_add_term(self.obj, item.0, negated as i32) }, ())?;
+        Ok(self)
+    }
+
+    /// A copy of the filter that also requires timestamps in
+    /// `start..end`.
+    fn and_time_range(&self, start: Timestamp, end: Timestamp) -> Result<EventFilter, Error> {
+        let mut range = EventFilter::new();
+        range.try_time_range(start, end)?;
+        self.and_filter(&range)
+    }
+
+    /// A filter matching the events that match both `self` and
+    /// `other`, with the clauses of both.
+    fn and_filter(&self, other: &EventFilter) -> Result<EventFilter, Error> {
+        if self.is_match_none() || other.is_match_none() {
+            return Ok(EventFilter::none());
+        }
+        let mut filter = EventFilter::new();
+        let mut empty = true;
+        for source in &[self, other] {
+            if source.is_match_all() {
+                continue;
+            }
+            for clause in source.clauses() {
+                if !empty {
+                    filter.try_and()?;
+                }
+                empty = false;
+                for term in clause.terms {
+                    match term {
+                        Term::Item { item, negated } => filter.add_term(item, negated)?,
+                        Term::TimeRange { start, end } => filter.try_time_range(start, end)?,
+                    };
+                }
+            }
+        }
+        if empty {
+            return Ok(EventFilter::all());
+        }
+        Ok(filter)
+    }
+
+    fn check_terms(&self) -> Result<(), Error> {
+        match self.matches {
+            Matches::Terms => Ok(()),
+            Matches::All | Matches::None => Err(Error::from(ErrorKind::IncorrectTermType)),
+        }
+    }
+
+    pub fn num_clauses(&self) -> u64 {
         unsafe { traildb_sys::tdb_event_filter_num_clauses(self.obj) }
     }
+
+    /// Number of terms in the clause at `clause`.
+    pub fn num_terms(&self, clause: u64) -> Result<u64, Error> {
+        let mut num_terms = 0;
+        let ret = unsafe { traildb_sys::tdb_event_filter_num_terms(self.obj, clause, &mut num_terms) };
+        wrap_tdb_err(ret, num_terms)
+    }
+
+    /// The term at `term` of the clause at `clause`.
+    pub fn term(&self, clause: u64, term: u64) -> Result<Term, Error> {
+        let mut term_type = traildb_sys::tdb_event_filter_term_type_TDB_EVENT_FILTER_UNKNOWN_TERM;
+        let ret = unsafe { traildb_sys::tdb_event_filter_get_term_type(self.obj, clause, term, &mut term_type) };
+        wrap_tdb_err(ret, ())?;
+        match term_type {
+            traildb_sys::tdb_event_filter_term_type_TDB_EVENT_FILTER_TIME_RANGE_TERM => {
+                let (mut start, mut end) = (0, 0);
+                let ret = unsafe {
+                    traildb_sys::tdb_event_filter_get_time_range(self.obj, clause, term, &mut start, &mut end)
+                };
+                wrap_tdb_err(ret, Term::TimeRange { start, end })
+            }
+            _ => {
+                let (mut item, mut negated) = (0, 0);
+                let ret = unsafe {
+                    traildb_sys::tdb_event_filter_get_item(self.obj, clause, term, &mut item, &mut negated)
+                };
+                wrap_tdb_err(ret, Term::Item { item: Item(item), negated: negated != 0 })
+            }
+        }
+    }
+
+    /// All clauses of the filter with their terms. A filter from
+    /// `EventFilter::all` or `EventFilter::none` has a single, empty
+    /// clause.
+    pub fn clauses(&self) -> Vec<Clause> {
+        (0..self.num_clauses())
+            .map(|clause| {
+                let num_terms = self.num_terms(clause).unwrap_or(0);
+                Clause { terms: (0..num_terms).filter_map(|term| self.term(clause, term).ok()).collect() }
+            })
+            .collect()
+    }
+
+    /// Render the filter in the syntax of `EventFilter::parse`, using
+    /// the field names and values of `db`.
+    ///
+    /// Values are quoted where the syntax needs it and items that are
+    /// not in `db` are shown as `#` followed by the raw item, so
+    /// `EventFilter::parse` reads the output back into the same filter.
+    /// Filters from `EventFilter::all` and `EventFilter::none` are shown
+    /// as `<match all>` and `<match none>`, which do not parse.
+    pub fn display<'a>(&'a self, db: &'a Db) -> FilterDisplay<'a> {
+        FilterDisplay { filter: self, db }
+    }
+}
+
+/// Displays an `EventFilter` with the names and values of a `Db`,
+/// created by `EventFilter::display`.
+pub struct FilterDisplay<'a> {
+    filter: &'a EventFilter,
+    db: &'a Db,
+}
+
+impl<'a> fmt::Display for FilterDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.filter.matches {
+            Matches::All => return write!(f, "<match all>"),
+            Matches::None => return write!(f, "<match none>"),
+            Matches::Terms => {}
+        }
+        for (i, clause) in self.filter.clauses().iter().enumerate() {
+            if i > 0 {
+                write!(f, " & ")?;
+            }
+            for (j, term) in clause.terms.iter().enumerate() {
+                if j > 0 {
+                    write!(f, " ")?;
+                }
+                match *term {
+                    Term::Item { item, negated } => {
+                        let name = self.db.get_field_name(item.field()).filter(|_| item.field() > 0);
+                        let value = self.db.get_item_bytes(item);
+                        let op = if negated { "!=" } else { "=" };
+                        match (name, value) {
+                            (Some(name), Some(value)) => {
+                                write!(f, "{}{}", name, op)?;
+                                parse::write_value(f, value)?;
+                            }
+                            _ => write!(f, "#{}{}", item.0, op)?,
+                        }
+                    }
+                    Term::TimeRange { start, end } => {
+                        write!(f, "time=")?;
+                        if start > 0 {
+                            write!(f, "{}", start)?;
+                        }
+                        write!(f, "..")?;
+                        if end < Timestamp::MAX {
+                            write!(f, "{}", end)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 
-impl<'b> Drop for EventFilter<'b> {
+impl Drop for EventFilter {
     fn drop(&mut self) {
         unsafe { traildb_sys::tdb_event_filter_free(self.obj) };
     }
 }
 
+// Filters are only modified through `&mut EventFilter`. Cursors only
+// read them.
+unsafe impl Send for EventFilter {}
+unsafe impl Sync for EventFilter {}
+
 
 
 
@@ -643,12 +1761,26 @@ impl<'b> Drop for EventFilter<'b> {
 mod tests {
     extern crate uuid;
     extern crate tempdir;
-    use super::{Constructor, Db, Cursor, MultiCursor, MultiEvent, EventFilter};
-    use std::cell::RefCell;
+    #[cfg(feature = "serde")]
+    extern crate serde_derive;
+    use super::{Constructor, ConstructorOptions, ConsOption, Db, DbOption, Cursor, ErrorKind, Format,
+                MultiCursor, EventFilter, Item, Trail, Clause, Term, FilterExpr, FilterExprError,
+                MAX_CNF_TERMS, ShardSet, convert, merge, merge_with_options, Index, IndexError};
+    use std::sync::Arc;
     use std::collections::HashSet;
     use std::iter::FromIterator;
+    use std::thread;
     use self::tempdir::TempDir;
 
+    /// The timestamps of the remaining events of the trail of `cursor`.
+    fn read_timestamps(cursor: &mut Cursor) -> Vec<u64> {
+        let mut timestamps = vec![];
+        while let Some(event) = cursor.next() {
+            timestamps.push(event.timestamp);
+        }
+        timestamps
+    }
+
     #[test]
     #[no_mangle]
     fn test_traildb() {
@@ -706,7 +1838,7 @@ mod tests {
 
         // Check round-trip get_uuid/get_trail_id
         for uuid in &uuids {
-            let trail_id = db.get_trail_id(&uuid).unwrap();
+            let trail_id = db.get_trail_id(uuid).unwrap();
             let uuid_rt = db.get_uuid(trail_id).unwrap();
             assert_eq!(&uuid, &uuid_rt);
         }
@@ -720,18 +1852,18 @@ mod tests {
         // test cursor
         let mut cursor = db.cursor();
         for uuid in &uuids {
-            let trail_id = db.get_trail_id(&uuid).unwrap();
+            let trail_id = db.get_trail_id(uuid).unwrap();
             cursor.get_trail(trail_id).unwrap();
             assert_eq!(events_per_trail, cursor.len());
         }
 
         // test db iterator
-        for trail in db.iter() {
+        for mut trail in db.iter() {
             // test trail iterator
-            for event in trail {
+            while let Some(event) = trail.next() {
                 // check that inserted event values match read values
-                for (item, item_ref) in event.items.into_iter().zip(field_vals.iter()) {
-                    let item = db.get_item_value(*item);
+                for (item, item_ref) in event.items.iter().zip(field_vals.iter()) {
+                    let item = db.get_item_value(*item).unwrap();
                     assert!(item.is_some());
                     assert_eq!(item.unwrap(), *item_ref);
                 }
@@ -739,6 +1871,109 @@ mod tests {
         }
     }
 
+    #[test]
+    fn constructor_options() {
+        let root = TempDir::new("traildb-tmp").unwrap().into_path();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+
+        // Defaults to writing a package when libarchive is available
+        let path = root.join("default");
+        let cons = Constructor::new(&path, &["field1"]).unwrap();
+        assert_eq!(Format::Package, cons.output_format().unwrap());
+        assert!(!cons.no_bigrams().unwrap());
+        drop(cons);
+
+        // A directory without bigrams
+        let path = root.join("directory");
+        let mut cons = ConstructorOptions::new()
+            .output_format(Format::Directory)
+            .no_bigrams(true)
+            .open(&path, &["field1"])
+            .unwrap();
+        assert_eq!(Format::Directory, cons.output_format().unwrap());
+        assert!(cons.no_bigrams().unwrap());
+        assert!(cons.add(&uuid, 1, &["a"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        assert!(path.is_dir());
+        assert_eq!(1, Db::open(&path).unwrap().num_events());
+
+        // A single-file package, set after the constructor was opened
+        let path = root.join("package");
+        let mut cons = Constructor::new(&path, &["field1"]).unwrap();
+        assert!(cons.set_option(ConsOption::OutputFormat(Format::Directory)).is_ok());
+        assert!(cons.set_option(ConsOption::OutputFormat(Format::Package)).is_ok());
+        assert!(cons.add(&uuid, 1, &["a"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        assert!(root.join("package.tdb").is_file());
+        assert_eq!(1, Db::open(&path).unwrap().num_events());
+    }
+
+    #[test]
+    fn errors() {
+        let root = TempDir::new("traildb-tmp").unwrap().into_path();
+
+        let path = root.join("missing");
+        let err = Db::open(&path).err().unwrap();
+        assert_eq!(ErrorKind::IoOpen, err.kind());
+        assert_eq!(-65, err.code());
+        assert_eq!("TDB_ERR_IO_OPEN", err.message());
+        assert_eq!(Some(path.as_path()), err.path());
+        assert_eq!(format!("could not open file (TDB_ERR_IO_OPEN), path `{}`", path.display()),
+                   err.to_string());
+
+        let path = root.join("fields");
+        let err = Constructor::new(&path, &["ok", "not ok"]).err().unwrap();
+        assert_eq!(ErrorKind::InvalidFieldname, err.kind());
+        assert_eq!(Some("not ok"), err.field());
+        let err = Constructor::new(&path, &["a", "b", "a"]).err().unwrap();
+        assert_eq!(ErrorKind::DuplicateFields, err.kind());
+        assert_eq!(Some("a"), err.field());
+        let err = Constructor::new(&path, &["a\0b"]).err().unwrap();
+        assert_eq!(ErrorKind::InvalidFieldname, err.kind());
+
+        let mut cons = Constructor::new(&path, &["a"]).unwrap();
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        assert_eq!(1, db.get_field("a").unwrap());
+        let err = db.get_field("b").err().unwrap();
+        assert_eq!(ErrorKind::UnknownField, err.kind());
+        assert_eq!(Some("b"), err.field());
+        assert_eq!(None, db.get_field_name(5));
+
+        assert_eq!(ErrorKind::Unknown(-1000), super::Error::from_code(-1000).kind());
+        assert_eq!("Unknown error", super::Error::from_code(-1000).message());
+    }
+
+    #[test]
+    fn binary_values() {
+        let mut path = TempDir::new("traildb-tmp").unwrap().into_path();
+        path.push("binary-values");
+
+        let hashed: &[u8] = &[0xde, 0xad, 0x00, 0xbe, 0xef];
+        let mut cons = Constructor::new(&path, &["id", "name"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add_bytes(&uuid, 1, &[hashed, b"alice"]).is_ok());
+        assert!(cons.add_bytes(&uuid, 2, &[b"", b"bob"]).is_ok());
+        assert!(cons.finalize().is_ok());
+
+        let db = Db::open(&path).unwrap();
+        let mut cursor = db.cursor();
+        assert!(cursor.get_trail(0).is_ok());
+        let first = cursor.next().unwrap().items.to_vec();
+        assert_eq!(Some(hashed), db.get_item_bytes(first[0]));
+        assert!(db.get_item_value(first[0]).is_err());
+        assert_eq!(Ok(Some("alice")), db.get_item_value(first[1]));
+        let second = cursor.next().unwrap();
+        assert_eq!(Some(&b""[..]), db.get_item_bytes(second.items[0]));
+        assert_eq!(Ok(None), db.get_item_value(second.items[0]));
+
+        assert_eq!(Some(first[0]), db.get_item(1, hashed));
+        assert_eq!(Some(first[1]), db.get_item(2, "alice"));
+        assert_eq!(vec![hashed], db.lexicon_bytes(1));
+        assert!(db.lexicon(1).is_err());
+        assert_eq!(vec!["alice", "bob"], db.lexicon(2).unwrap());
+    }
+
     #[test]
     fn test_multi_cursor() {
         let field_names = ["field1"];
@@ -763,54 +1998,84 @@ mod tests {
 
         let db = Db::open(&path).unwrap();
 
-        let cursors = vec![RefCell::new(db.cursor()), RefCell::new(db.cursor())];
-        let mut multi_cursor = MultiCursor::new(&cursors);
-        let mut cursor1 = cursors[0].borrow_mut();
-        let mut cursor2 = cursors[1].borrow_mut();
-
-        assert!(cursor1.get_trail(0).is_ok());
-        assert!(cursor2.get_trail(1).is_ok());
-        multi_cursor.reset();
-
-        let multi_events: Vec<MultiEvent> = multi_cursor.collect();
-        assert_eq!(vec![10, 11, 12, 20, 21, 22],
-                   multi_events.iter().map(|me| me.event.timestamp).collect::<Vec<u64>>());
-        assert_eq!(HashSet::from_iter(vec![0, 0, 0, 1, 1, 1].into_iter()),
-                   multi_events.iter().map(|me| me.cursor_idx).collect::<HashSet<usize>>());
-
-        // TODO: Test dropping cursors, should not be allowed if multi
-        // cursor is still around.
+        let mut multi_cursor = MultiCursor::new(vec![db.cursor(), db.cursor()]);
+        assert_eq!(2, multi_cursor.len());
+        assert!(multi_cursor.get_trail(0, 0).is_ok());
+        assert!(multi_cursor.get_trail(1, 1).is_ok());
+        assert_eq!(Some(10), multi_cursor.peek().map(|me| me.event.timestamp));
+
+        // A batch may stop early where a cursor needs to decode more
+        let batch: Vec<u64> = multi_cursor.next_batch(2).iter().map(|me| me.event.timestamp).collect();
+        assert_eq!(vec![10, 11], batch);
+        let mut timestamps = vec![];
+        loop {
+            let batch = multi_cursor.next_batch(2);
+            if batch.is_empty() {
+                break;
+            }
+            timestamps.extend(batch.iter().map(|me| me.event.timestamp));
+        }
+        assert_eq!(vec![12, 20, 21, 22], timestamps);
+        assert!(multi_cursor.peek().is_none());
+        assert!(multi_cursor.next_batch(4).is_empty());
+
+        // Moving a member cursor resets the merge
+        assert_eq!(Some(ErrorKind::InvalidTrailId),
+                   multi_cursor.get_trail(0, 2).err().map(|e| e.kind()));
+        assert!(multi_cursor.get_trail(1, 0).is_ok());
+        let mut count = 0;
+        while multi_cursor.next().is_some() {
+            count += 1;
+        }
+        assert_eq!(3, count);
+
+        // Borrowed cursors are given back when the multi-cursor is dropped
+        let mut cursor1 = db.cursor();
+        let mut cursor2 = db.cursor();
+        {
+            let mut multi_cursor = MultiCursor::new(vec![&mut cursor1, &mut cursor2]);
+            assert!(multi_cursor.get_trail(0, 0).is_ok());
+            assert!(multi_cursor.get_trail(1, 0).is_ok());
+            let mut cursor_idxs = vec![];
+            while let Some(me) = multi_cursor.next() {
+                cursor_idxs.push(me.cursor_idx);
+            }
+            assert_eq!(6, cursor_idxs.len());
+            assert_eq!(HashSet::from_iter(vec![0, 1].into_iter()),
+                       cursor_idxs.into_iter().collect::<HashSet<usize>>());
+        }
+        assert!(cursor1.get_trail(1).is_ok());
+        assert_eq!(3, cursor1.len());
+        assert!(cursor2.next().is_none());
     }
 
 
     #[test]
+    #[allow(deprecated)]
     fn filters() {
         let mut path = TempDir::new("traildb-tmp").unwrap().into_path();
         path.push("filters");
 
-        let mut cons = Constructor::new(&path, &vec!["field1", "field2"]).unwrap();
+        let mut cons = Constructor::new(&path, &["field1", "field2"]).unwrap();
 
         let uuid = *uuid::Uuid::new_v4().as_bytes();
 
-        assert!(cons.add(&uuid, 0, &vec!["a", "0"]).is_ok());
-        assert!(cons.add(&uuid, 1, &vec!["b", "1"]).is_ok());
-        assert!(cons.add(&uuid, 2, &vec!["a", "2"]).is_ok());
-        assert!(cons.add(&uuid, 3, &vec!["c", "3"]).is_ok());
-        assert!(cons.add(&uuid, 4, &vec!["a", "4"]).is_ok());
-        assert!(cons.add(&uuid, 5, &vec!["d", "5"]).is_ok());
+        assert!(cons.add(&uuid, 0, &["a", "0"]).is_ok());
+        assert!(cons.add(&uuid, 1, &["b", "1"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["a", "2"]).is_ok());
+        assert!(cons.add(&uuid, 3, &["c", "3"]).is_ok());
+        assert!(cons.add(&uuid, 4, &["a", "4"]).is_ok());
+        assert!(cons.add(&uuid, 5, &["d", "5"]).is_ok());
         assert!(cons.finalize().is_ok());
 
         // Return a Vec with timestamps of event returned when the
         // given filter is applied.
-        fn timestamps(c: &mut Cursor, f: &EventFilter) -> Vec<u64> {
+        fn timestamps(db: &Db, f: &EventFilter) -> Vec<u64> {
+            let mut c = db.cursor();
             assert!(c.get_trail(0).is_ok());
             assert!(c.set_filter(f).is_ok());
 
-            let mut result = vec![];
-            for event in c {
-                result.push(event.timestamp);
-            }
-            result
+            read_timestamps(&mut c)
         }
 
 
@@ -819,22 +2084,20 @@ mod tests {
         let field1 = fields.get("field1").unwrap();
         let field2 = fields.get("field2").unwrap();
 
-        let mut cursor = db.cursor();
-
         let mut f = EventFilter::new();
 
         // Empty filter doesn't match any events
         assert_eq!(1, f.num_clauses());
-        assert_eq!(0, timestamps(&mut cursor, &f).len());
+        assert_eq!(0, timestamps(&db, &f).len());
 
         // Events with field1=a
         f.or(db.get_item(*field1, "a").unwrap());
-        assert_eq!(vec![0, 2, 4], timestamps(&mut cursor, &f));
+        assert_eq!(vec![0, 2, 4], timestamps(&db, &f));
 
         // Calling '.or(...)' again adds another OR clause
         // the filter is now: field1=a OR field1=b
         f.or(db.get_item(*field1, "b").unwrap());
-        assert_eq!(vec![0, 1, 2, 4], timestamps(&mut cursor, &f));
+        assert_eq!(vec![0, 1, 2, 4], timestamps(&db, &f));
 
         drop(f);
 
@@ -843,13 +2106,13 @@ mod tests {
         let mut f = EventFilter::new();
         f.or(db.get_item(*field1, "a").unwrap())
             .or(db.get_item(*field1, "b").unwrap());
-        assert_eq!(vec![0, 1, 2, 4], timestamps(&mut cursor, &f));
+        assert_eq!(vec![0, 1, 2, 4], timestamps(&db, &f));
         drop(f);
 
         // NOT field1=a
         let mut f = EventFilter::new();
         f.or(db.get_item(*field1, "a").unwrap());
-        assert_eq!(vec![0, 2, 4], timestamps(&mut cursor, &f));
+        assert_eq!(vec![0, 2, 4], timestamps(&db, &f));
         drop(f);
 
         // field1=a AND (field2=0 OR field2=2)
@@ -858,26 +2121,1128 @@ mod tests {
             .and()
             .or(db.get_item(*field2, "0").unwrap())
             .or(db.get_item(*field2, "2").unwrap());
-        assert_eq!(vec![0, 2], timestamps(&mut cursor, &f));
+        assert_eq!(vec![0, 2], timestamps(&db, &f));
         drop(f);
 
         // start_time <= timestamp < end_time
         let mut f = EventFilter::new();
         f.time_range(2, 4);
-        assert_eq!(vec![2, 3], timestamps(&mut cursor, &f));
+        assert_eq!(vec![2, 3], timestamps(&db, &f));
+        drop(f);
+
+        // Empty time ranges are rejected
+        let mut f = EventFilter::new();
+        assert_eq!(Some(ErrorKind::InvalidRange), f.try_time_range(4, 4).err().map(|e| e.kind()));
+        assert_eq!(Some(ErrorKind::InvalidRange), f.try_time_range(4, 2).err().map(|e| e.kind()));
+        f.time_range(4, 2);
+        assert_eq!(Ok(0), f.num_terms(0).map_err(|e| e.kind()));
+        assert!(f.try_or(db.get_item(*field1, "a").unwrap())
+                 .and_then(|f| f.try_and())
+                 .and_then(|f| f.try_time_range(0, 3))
+                 .is_ok());
+        assert_eq!(vec![0, 2], timestamps(&db, &f));
         drop(f);
 
         // EventFilter::all() always matches all events.
         let mut f = EventFilter::all();
-        assert_eq!(6, timestamps(&mut cursor, &f).len());
-        // TODO: Adding a term to a match_all filter doesn't make
-        // sense. Should it fail?
+        assert_eq!(6, timestamps(&db, &f).len());
+        // Adding a term to a match_all filter doesn't make sense, it is
+        // ignored
         f.or(db.get_item(*field1, "a").unwrap());
-        assert_eq!(6, timestamps(&mut cursor, &f).len());
+        assert_eq!(6, timestamps(&db, &f).len());
+        let item = db.get_item(*field1, "a").unwrap();
+        assert_eq!(Some(ErrorKind::IncorrectTermType), f.try_or(item).err().map(|e| e.kind()));
+        assert_eq!(Some(ErrorKind::IncorrectTermType), f.try_or_not(item).err().map(|e| e.kind()));
+        assert_eq!(Some(ErrorKind::IncorrectTermType), f.try_and().err().map(|e| e.kind()));
+        assert_eq!(Some(ErrorKind::IncorrectTermType), f.try_time_range(0, 1).err().map(|e| e.kind()));
+        assert_eq!(6, timestamps(&db, &f).len());
         drop(f);
 
         // EventFilter::none() always matches all events.
         let f = EventFilter::none();
-        assert_eq!(0, timestamps(&mut cursor, &f).len());
+        assert_eq!(0, timestamps(&db, &f).len());
+    }
+
+    #[test]
+    fn db_options() {
+        let mut path = TempDir::new("traildb-tmp").unwrap().into_path();
+        path.push("db-options");
+
+        let mut cons = Constructor::new(&path, &["field1"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 0, &["a"]).is_ok());
+        assert!(cons.add(&uuid, 1, &["b"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["a"]).is_ok());
+        assert!(cons.add(&uuid, 3, &["a"]).is_ok());
+        assert!(cons.finalize().is_ok());
+
+        fn timestamps(db: &Db) -> Vec<u64> {
+            let mut cursor = db.cursor();
+            assert!(cursor.get_trail(0).is_ok());
+            read_timestamps(&mut cursor)
+        }
+
+        let mut db = Db::open(&path).unwrap();
+        assert!(!db.only_diff_items().unwrap());
+        assert!(db.event_filter().is_none());
+
+        // The db keeps the filter alive after we drop our handle
+        let mut f = EventFilter::new();
+        f.try_or(db.get_item(1, "a").unwrap()).unwrap();
+        let f = Arc::new(f);
+        assert!(db.set_option(DbOption::EventFilter(Some(f.clone()))).is_ok());
+        drop(f);
+        assert_eq!(vec![0, 2, 3], timestamps(&db));
+        assert_eq!(Err(ErrorKind::OnlyDiffFilter), db.set_option(DbOption::OnlyDiffItems(true)).map_err(|e| e.kind()));
+
+        assert!(db.set_option(DbOption::EventFilter(None)).is_ok());
+        assert!(db.event_filter().is_none());
+        assert_eq!(vec![0, 1, 2, 3], timestamps(&db));
+
+        // Unchanged values are left out of the events
+        assert!(db.set_option(DbOption::OnlyDiffItems(true)).is_ok());
+        assert!(db.only_diff_items().unwrap());
+        let mut cursor = db.cursor();
+        assert!(cursor.get_trail(0).is_ok());
+        let mut num_items = vec![];
+        while let Some(event) = cursor.next() {
+            num_items.push(event.items.len());
+        }
+        assert_eq!(vec![1, 1, 1, 0], num_items);
+        drop(cursor);
+        let f = Arc::new(EventFilter::all());
+        assert_eq!(Err(ErrorKind::OnlyDiffFilter), db.set_option(DbOption::EventFilter(Some(f))).map_err(|e| e.kind()));
+        assert!(db.set_option(DbOption::OnlyDiffItems(false)).is_ok());
+
+        assert!(db.set_option(DbOption::CursorEventBufferSize(2)).is_ok());
+        assert_eq!(2, db.cursor_event_buffer_size().unwrap());
+        assert_eq!(vec![0, 1, 2, 3], timestamps(&db));
+        assert_eq!(Err(ErrorKind::InvalidOptionValue),
+                   db.set_option(DbOption::CursorEventBufferSize(0)).map_err(|e| e.kind()));
+    }
+
+    #[test]
+    fn trail_filters() {
+        let mut path = TempDir::new("traildb-tmp").unwrap().into_path();
+        path.push("trail-filters");
+
+        let mut cons = Constructor::new(&path, &["user"]).unwrap();
+        for i in 0..3 {
+            let uuid = [i as u8; 16];
+            assert!(cons.add(&uuid, 10 * i, &["real"]).is_ok());
+            assert!(cons.add(&uuid, 10 * i + 1, &["test"]).is_ok());
+            assert!(cons.add(&uuid, 10 * i + 2, &["real"]).is_ok());
+        }
+        assert!(cons.finalize().is_ok());
+
+        fn timestamps(db: &Db, trail_id: u64) -> Vec<u64> {
+            let mut cursor = db.cursor();
+            assert!(cursor.get_trail(trail_id).is_ok());
+            read_timestamps(&mut cursor)
+        }
+
+        let mut db = Db::open(&path).unwrap();
+        let mut hide_test = EventFilter::new();
+        hide_test.try_or_not(db.get_item(1, "test").unwrap()).unwrap();
+        let hide_test = Arc::new(hide_test);
+
+        // One filter shared by two trails
+        assert!(db.set_trail_filter(0, hide_test.clone()).is_ok());
+        assert!(db.set_trail_filter(2, hide_test.clone()).is_ok());
+        assert!(db.trail_filter(1).is_none());
+        assert_eq!(vec![0, 2], timestamps(&db, 0));
+        assert_eq!(vec![10, 11, 12], timestamps(&db, 1));
+        assert_eq!(vec![20, 22], timestamps(&db, 2));
+
+        // Trail filters take precedence over the db-wide filter
+        let f = Arc::new(EventFilter::none());
+        assert!(db.set_option(DbOption::EventFilter(Some(f))).is_ok());
+        assert_eq!(vec![0, 2], timestamps(&db, 0));
+        assert!(timestamps(&db, 1).is_empty());
+        assert!(db.set_option(DbOption::EventFilter(None)).is_ok());
+
+        // A cursor filter takes precedence over trail filters
+        let all = EventFilter::all();
+        let mut cursor = db.cursor();
+        assert!(cursor.set_filter(&all).is_ok());
+        assert!(cursor.get_trail(0).is_ok());
+        assert_eq!(3, cursor.len());
+        drop(cursor);
+
+        assert_eq!(Err(ErrorKind::OnlyDiffFilter), db.set_option(DbOption::OnlyDiffItems(true)).map_err(|e| e.kind()));
+        assert!(db.clear_trail_filter(0).unwrap().is_some());
+        assert!(db.clear_trail_filter(0).unwrap().is_none());
+        assert!(db.clear_trail_filter(2).unwrap().is_some());
+        assert_eq!(vec![0, 1, 2], timestamps(&db, 0));
+
+        assert_eq!(Err(ErrorKind::InvalidTrailId), db.set_trail_filter(3, hide_test).map_err(|e| e.kind()));
+    }
+
+    #[test]
+    fn threads() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<Db>();
+        assert_sync::<Db>();
+        assert_send::<Cursor>();
+        assert_send::<Trail>();
+        assert_send::<Constructor>();
+        assert_send::<EventFilter>();
+        assert_sync::<EventFilter>();
+
+        let tmp_dir = TempDir::new("tdb_threads").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["field"]).unwrap();
+        for i in 0..8u64 {
+            let uuid = *uuid::Uuid::new_v4().as_bytes();
+            for j in 0..=i {
+                assert!(cons.add(&uuid, j, &["x"]).is_ok());
+            }
+        }
+        assert!(cons.finalize().is_ok());
+
+        // Share one Db between threads, each with its own cursor
+        let db = Arc::new(Db::open(&path).unwrap());
+        let handles: Vec<_> = (0..2)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let mut cursor = db.cursor();
+                    let mut count = 0;
+                    for id in (t..db.num_trails()).step_by(2) {
+                        cursor.get_trail(id).unwrap();
+                        count += cursor.len() as usize;
+                    }
+                    count
+                })
+            })
+            .collect();
+        let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(db.num_events() as usize, total);
+
+        // A cursor can be moved to another thread
+        let db = Db::open(&path).unwrap();
+        let mut cursor = db.cursor();
+        cursor.get_trail(7).unwrap();
+        let expected = read_timestamps(&mut cursor);
+        cursor.get_trail(7).unwrap();
+        let count = thread::scope(|s| s.spawn(move || read_timestamps(&mut cursor)).join().unwrap());
+        assert_eq!(expected, count);
+    }
+
+    #[test]
+    fn trails() {
+        let tmp_dir = TempDir::new("tdb_trails").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["field"]).unwrap();
+        let mut uuids = vec![];
+        for i in 0..10u64 {
+            let uuid = *uuid::Uuid::new_v4().as_bytes();
+            for j in 0..=i {
+                assert!(cons.add(&uuid, j, &["x"]).is_ok());
+            }
+            uuids.push(uuid);
+        }
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+
+        let mut ids = vec![];
+        let mut events = 0;
+        let mut trails = db.trails();
+        while let Some((id, cursor)) = trails.next_trail() {
+            ids.push(id);
+            events += cursor.len();
+        }
+        assert_eq!((0..10).collect::<Vec<_>>(), ids);
+        assert_eq!(db.num_events(), events);
+
+        // The range is clamped to the number of trails
+        let mut ids = vec![];
+        db.trails_in(7..100).for_each(|id, _| ids.push(id));
+        assert_eq!(vec![7, 8, 9], ids);
+        assert!(db.trails_in(10..20).next_trail().is_none());
+
+        // Unknown UUIDs are skipped, the order is kept
+        let unknown = *uuid::Uuid::new_v4().as_bytes();
+        let mut lengths = vec![];
+        db.trails_for_uuids(&[uuids[4], unknown, uuids[2]])
+            .for_each(|id, cursor| lengths.push((id, cursor.len())));
+        assert_eq!(vec![(db.get_trail_id(&uuids[4]).unwrap(), 5),
+                        (db.get_trail_id(&uuids[2]).unwrap(), 3)],
+                   lengths);
+    }
+
+    #[test]
+    fn seek_to_timestamp() {
+        let tmp_dir = TempDir::new("tdb_seek").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["field"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        for t in 0..1000u64 {
+            assert!(cons.add(&uuid, t * 2, &[&t.to_string()]).is_ok());
+        }
+        assert!(cons.finalize().is_ok());
+        let mut db = Db::open(&path).unwrap();
+        // Small batches, so seeking crosses batch boundaries
+        assert!(db.set_option(DbOption::CursorEventBufferSize(10)).is_ok());
+
+        let mut cursor = db.cursor();
+        cursor.get_trail(0).unwrap();
+        assert_eq!(Some(0), cursor.peek().map(|e| e.timestamp));
+        assert!(cursor.seek_to_timestamp(101));
+        assert_eq!(Some(102), cursor.peek().map(|e| e.timestamp));
+        assert_eq!(Some(102), cursor.next().map(|e| e.timestamp));
+        assert_eq!(Some("52"), cursor.peek().and_then(|e| db.get_item_value(e.items[0]).unwrap()));
+        // Seeking backwards stays at the next event
+        assert!(cursor.seek_to_timestamp(50));
+        assert_eq!(Some(104), cursor.next().map(|e| e.timestamp));
+        assert!(cursor.seek_to_timestamp(1998));
+        assert_eq!(vec![1998], read_timestamps(&mut cursor));
+        assert!(cursor.peek().is_none());
+
+        cursor.get_trail(0).unwrap();
+        assert!(!cursor.seek_to_timestamp(2000));
+        assert!(cursor.next().is_none());
+    }
+
+    #[test]
+    fn shard_set() {
+        let tmp_dir = TempDir::new("tdb_shard_set").unwrap();
+        let user = *uuid::Uuid::new_v4().as_bytes();
+        let other = *uuid::Uuid::new_v4().as_bytes();
+        let mut paths = vec![];
+        // Each day has its own fields and lexicon
+        for (day, fields) in [["page"], ["url"]].iter().enumerate() {
+            let path = tmp_dir.path().join(format!("day{}", day));
+            let mut cons = Constructor::new(&path, fields).unwrap();
+            for t in 0..3u64 {
+                let value = format!("{}-{}", fields[0], t);
+                assert!(cons.add(&user, t * 2 + day as u64, &[&value]).is_ok());
+            }
+            if day == 1 {
+                assert!(cons.add(&other, 10, &["x"]).is_ok());
+            }
+            assert!(cons.finalize().is_ok());
+            paths.push(path);
+        }
+        let shards = ShardSet::open(&paths).unwrap();
+        assert_eq!(2, shards.len());
+
+        let mut trail = shards.trail_for_uuid(&user);
+        assert_eq!(&[0, 1], trail.shards());
+        let mut events: Vec<(usize, u64, String)> = vec![];
+        while let Some(e) = trail.next() {
+            let record = e.decode().unwrap();
+            let (name, value) = record.iter().next().unwrap();
+            events.push((e.shard, record.timestamp, format!("{}={}", name, value)));
+        }
+        assert_eq!(vec![(0, 0, "page=page-0".to_owned()),
+                        (1, 1, "url=url-0".to_owned()),
+                        (0, 2, "page=page-1".to_owned()),
+                        (1, 3, "url=url-1".to_owned()),
+                        (0, 4, "page=page-2".to_owned()),
+                        (1, 5, "url=url-2".to_owned())],
+                   events);
+
+        let mut trail = shards.trail_for_uuid(&other);
+        assert_eq!(&[1], trail.shards());
+        assert_eq!(Some((1, 10)), trail.next().map(|e| (e.shard, e.event.timestamp)));
+        assert!(trail.next().is_none());
+        assert!(shards.trail_for_uuid(&[0; 16]).next().is_none());
+        assert!(ShardSet::open(&[tmp_dir.path().join("missing")]).is_err());
+    }
+
+    #[test]
+    fn events_between() {
+        let tmp_dir = TempDir::new("tdb_events_between").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["field"]).unwrap();
+        let mut uuids = vec![];
+        for i in 0..10u64 {
+            let uuid = *uuid::Uuid::new_v4().as_bytes();
+            for j in 0..5 {
+                let value = if j % 2 == 0 { "a" } else { "b" };
+                assert!(cons.add(&uuid, i * 10 + j, &[value]).is_ok());
+            }
+            uuids.push(uuid);
+        }
+        assert!(cons.finalize().is_ok());
+        let mut db = Db::open(&path).unwrap();
+
+        assert_eq!(Some((50, 54)), db.trail_time_range(db.get_trail_id(&uuids[5]).unwrap()));
+        assert_eq!(None, db.trail_time_range(10));
+
+        let between = |db: &Db, start, end| -> Vec<([u8; 16], u64)> {
+            let mut between = db.events_between(start, end).unwrap();
+            let mut events = vec![];
+            while let Some((id, e)) = between.next_event() {
+                events.push((*db.get_uuid(id).unwrap(), e.timestamp));
+            }
+            events.sort_by_key(|&(_, t)| t);
+            events
+        };
+        assert_eq!(vec![(uuids[2], 23), (uuids[2], 24), (uuids[3], 30), (uuids[3], 31), (uuids[3], 32),
+                        (uuids[3], 33), (uuids[3], 34), (uuids[4], 40), (uuids[4], 41)],
+                   between(&db, 23, 42));
+        assert!(between(&db, 100, 200).is_empty());
+        assert!(between(&db, 5, 10).is_empty());
+        assert_eq!(Some(ErrorKind::InvalidRange), db.events_between(5, 5).err().map(|e| e.kind()));
+
+        // The filters of the db still apply
+        let mut filter = EventFilter::new();
+        filter.try_or(db.get_item(1, "a").unwrap()).unwrap();
+        assert!(db.set_option(DbOption::EventFilter(Some(Arc::new(filter)))).is_ok());
+        let trail3 = db.get_trail_id(&uuids[3]).unwrap();
+        assert!(db.set_trail_filter(trail3, Arc::new(EventFilter::none())).is_ok());
+        assert_eq!(vec![(uuids[2], 24), (uuids[4], 40)], between(&db, 23, 42));
+
+        // The range can't be applied to only-diff items, the time
+        // ranges of the trails can still be read
+        let mut db = Db::open(&path).unwrap();
+        assert!(db.set_option(DbOption::OnlyDiffItems(true)).is_ok());
+        assert_eq!(Some(ErrorKind::OnlyDiffFilter), db.events_between(23, 42).err().map(|e| e.kind()));
+        assert_eq!(Some((50, 54)), db.trail_time_range(db.get_trail_id(&uuids[5]).unwrap()));
+    }
+
+    #[test]
+    fn merge_dbs() {
+        let tmp_dir = TempDir::new("tdb_merge").unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        let mut dbs = vec![];
+        // The third day adds a field and drops another
+        for (day, fields) in [["page", "user"], ["page", "user"], ["user", "url"]].iter().enumerate() {
+            let path = tmp_dir.path().join(format!("day{}", day));
+            let mut cons = Constructor::new(&path, fields).unwrap();
+            for t in 0..2u64 {
+                let values: Vec<String> = fields.iter()
+                    .map(|&f| if f == "user" { "alice".to_owned() } else { format!("{}-{}", f, t) })
+                    .collect();
+                let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+                assert!(cons.add(&uuid, day as u64 * 10 + t, &values).is_ok());
+            }
+            assert!(cons.finalize().is_ok());
+            dbs.push(Db::open(&path).unwrap());
+        }
+        let mut filter = EventFilter::new();
+        filter.try_or(dbs[2].get_item(2, "url-1").unwrap()).unwrap();
+        assert!(dbs[2].set_option(DbOption::EventFilter(Some(Arc::new(filter)))).is_ok());
+
+        let path = tmp_dir.path().join("month");
+        let mut options = ConstructorOptions::new();
+        options.output_format(Format::Directory);
+        assert!(merge_with_options(&dbs, &path, &options).is_ok());
+        let db = Db::open(&path).unwrap();
+        assert_eq!(vec!["time", "page", "user", "url"],
+                   (0..4).map(|f| db.get_field_name(f).unwrap()).collect::<Vec<_>>());
+        let events = |db: &Db| -> Vec<(u64, Vec<String>)> {
+            let mut cursor = db.cursor();
+            assert!(cursor.get_trail(db.get_trail_id(&uuid).unwrap()).is_ok());
+            let mut events = vec![];
+            while let Some(event) = cursor.next() {
+                let record = event.decode(db).unwrap();
+                events.push((record.timestamp, record.iter().map(|(_, value)| value.to_owned()).collect()));
+            }
+            events
+        };
+        let event = |t, page: &str, url: &str| (t, vec![page.to_owned(), "alice".to_owned(), url.to_owned()]);
+        assert_eq!(vec![event(0, "page-0", ""), event(1, "page-1", ""), event(10, "page-0", ""),
+                        event(11, "page-1", ""), event(21, "", "url-1")],
+                   events(&db));
+
+        // Values left out of only-diff events are carried over
+        assert!(dbs[2].set_option(DbOption::EventFilter(None)).is_ok());
+        assert!(dbs[2].set_option(DbOption::OnlyDiffItems(true)).is_ok());
+        let path = tmp_dir.path().join("only_diff");
+        assert!(merge(&dbs[1..], &path).is_ok());
+        assert_eq!(vec![event(10, "page-0", ""), event(11, "page-1", ""), event(20, "", "url-0"),
+                        event(21, "", "url-1")],
+                   events(&Db::open(&path).unwrap()));
+        // Also when the fields are the same as those of the merge
+        assert!(dbs[1].set_option(DbOption::OnlyDiffItems(true)).is_ok());
+        let path = tmp_dir.path().join("only_diff_same_fields");
+        assert!(merge(&dbs[..2], &path).is_ok());
+        let event = |t, page: &str| (t, vec![page.to_owned(), "alice".to_owned()]);
+        assert_eq!(vec![event(0, "page-0"), event(1, "page-1"), event(10, "page-0"), event(11, "page-1")],
+                   events(&Db::open(&path).unwrap()));
+
+        let path = tmp_dir.path().join("same_fields");
+        assert!(merge(&dbs[..2], &path).is_ok());
+        assert_eq!(4, Db::open(&path).unwrap().num_events());
+        let path = tmp_dir.path().join("empty");
+        assert!(merge(&[], &path).is_ok());
+        assert_eq!(0, Db::open(&path).unwrap().num_trails());
+    }
+
+    #[test]
+    fn index() {
+        let tmp_dir = TempDir::new("tdb_index").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut options = ConstructorOptions::new();
+        options.output_format(Format::Directory);
+        let mut cons = options.open(&path, &["kind"]).unwrap();
+        let mut rare = HashSet::new();
+        for i in 0..20 {
+            let uuid = *uuid::Uuid::new_v4().as_bytes();
+            assert!(cons.add(&uuid, 1, &["common"]).is_ok());
+            if i % 7 == 0 {
+                assert!(cons.add(&uuid, 2, &["rare"]).is_ok());
+                rare.insert(uuid);
+            }
+        }
+        assert!(cons.finalize().is_ok());
+        let mut db = Db::open(&path).unwrap();
+
+        let index = Index::build(&db);
+        let mut filter = EventFilter::new();
+        filter.try_or(db.get_item(1, "rare").unwrap()).unwrap();
+        let candidates = index.candidates(&filter);
+        assert_eq!(rare, candidates.iter().map(|&id| *db.get_uuid(id).unwrap()).collect());
+        let mut negated = EventFilter::new();
+        negated.try_or_not(db.get_item(1, "common").unwrap()).unwrap();
+        assert_eq!(20, index.candidates(&negated).len());
+        assert!(index.candidates(&EventFilter::none()).is_empty());
+
+        let visited = |db: &Db| -> (usize, u64) {
+            let (mut trails, mut events) = (0, 0);
+            db.filtered_trails(&filter).unwrap().for_each(|_, cursor| {
+                trails += 1;
+                events += cursor.len();
+            });
+            (trails, events)
+        };
+        assert_eq!((20, 3), visited(&db));
+
+        let index_path = Index::default_path(&path);
+        assert_eq!(path.join("index"), index_path);
+        assert_eq!(None, Index::find(&path));
+        assert!(index.save(&index_path).is_ok());
+        assert_eq!(Some(index_path.clone()), Index::find(&path));
+        let loaded = Index::load(&db, &index_path).unwrap();
+        assert_eq!(index, loaded);
+        assert!(db.set_index(Some(Arc::new(loaded))).is_ok());
+        assert_eq!((3, 3), visited(&db));
+
+        // The filters of the database still apply: a db-wide filter
+        // hides the rare events, and a trail filter shows one again
+        let mut first = EventFilter::new();
+        first.try_time_range(0, 2).unwrap();
+        assert!(db.set_option(DbOption::EventFilter(Some(Arc::new(first)))).is_ok());
+        assert_eq!((3, 0), visited(&db));
+        assert!(db.set_trail_filter(candidates[0], Arc::new(EventFilter::all())).is_ok());
+        assert_eq!((3, 1), visited(&db));
+        assert!(db.clear_trail_filter(candidates[0]).is_ok());
+        assert!(db.set_option(DbOption::EventFilter(None)).is_ok());
+        assert_eq!((3, 3), visited(&db));
+
+        // Only-diff items can't be filtered, but index the same
+        let built = Index::build(&db);
+        assert!(db.set_option(DbOption::OnlyDiffItems(true)).is_ok());
+        assert_eq!(built, Index::build(&db));
+        assert_eq!(Some(ErrorKind::OnlyDiffFilter), db.filtered_trails(&filter).err().map(|e| e.kind()));
+        assert!(db.set_option(DbOption::OnlyDiffItems(false)).is_ok());
+
+        let other_path = tmp_dir.path().join("other");
+        let mut cons = Constructor::new(&other_path, &["kind"]).unwrap();
+        assert!(cons.add(&[1; 16], 1, &["rare"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let mut other = Db::open(&other_path).unwrap();
+        match Index::load(&other, &index_path) {
+            Err(IndexError::Mismatch) => {}
+            res => panic!("expected a mismatch, got {:?}", res),
+        }
+        assert!(other.set_index(Some(Arc::new(index))).is_err());
+        std::fs::write(&index_path, b"TDBINDEX").unwrap();
+        match Index::load(&db, &index_path) {
+            Err(IndexError::Corrupt) => {}
+            res => panic!("expected a corrupted index, got {:?}", res),
+        }
+        #[cfg(feature = "rayon")]
+        assert_eq!(Index::build(&db), Index::par_build(&db));
+    }
+
+    #[test]
+    fn convert_formats() {
+        let tmp_dir = TempDir::new("tdb_convert").unwrap();
+        let path = tmp_dir.path().join("dir");
+        let mut options = ConstructorOptions::new();
+        options.output_format(Format::Directory);
+        let mut cons = options.open(&path, &["field"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 1, &["a"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["b"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        assert_eq!(Format::Directory, Db::open(&path).unwrap().format());
+
+        let pkg_path = tmp_dir.path().join("pkg");
+        assert!(convert(&path, &pkg_path, Format::Package).is_ok());
+        assert!(tmp_dir.path().join("pkg.tdb").is_file());
+        let db = Db::open(&pkg_path).unwrap();
+        assert_eq!(Format::Package, db.format());
+        assert_eq!(Format::Package, Db::open(tmp_dir.path().join("pkg.tdb")).unwrap().format());
+        assert_eq!(2, db.num_events());
+        assert_eq!(Some("b"), db.get_item_value(db.get_item(1, "b").unwrap()).unwrap());
+
+        let dir_path = tmp_dir.path().join("dir2");
+        assert!(convert(&pkg_path, &dir_path, Format::Directory).is_ok());
+        let db = Db::open(&dir_path).unwrap();
+        assert_eq!(Format::Directory, db.format());
+        assert_eq!(2, db.num_events());
+
+        std::fs::write(tmp_dir.path().join("bad.tdb"), b"not a package").unwrap();
+        assert_eq!(Some(ErrorKind::InvalidPackage), Db::open(tmp_dir.path().join("bad")).err().map(|e| e.kind()));
+        assert!(convert(tmp_dir.path().join("missing"), &dir_path, Format::Package).is_err());
+    }
+
+    #[test]
+    fn decode() {
+        let tmp_dir = TempDir::new("tdb_decode").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["user", "action"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 1, &["alice", "open"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["alice", ""]).is_ok());
+        assert!(cons.add_bytes(&uuid, 3, &[b"alice", b"\xff"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+
+        for item in db.get_trail(0).unwrap().next().unwrap().items {
+            assert_eq!(Some(*item), db.get_item(item.field(), db.get_item_bytes(*item).unwrap()));
+        }
+
+        let mut cursor = db.cursor();
+        cursor.get_trail(0).unwrap();
+        let record = cursor.next().unwrap().decode(&db).unwrap();
+        assert_eq!(1, record.timestamp);
+        assert_eq!(2, record.len());
+        assert_eq!("alice", &record["user"]);
+        assert_eq!("open", &record[2]);
+        assert_eq!(Some("open"), record.get(&"action".to_owned()));
+        assert_eq!(None, record.get("time"));
+        assert_eq!(vec![("user", "alice"), ("action", "open")], record.iter().collect::<Vec<_>>());
+
+        // The owned event outlives the cursor
+        let owned = record.to_owned_event(&uuid);
+        let record = cursor.next().unwrap().decode(&db).unwrap();
+        assert_eq!(Some(""), record.get("action"));
+        assert!(cursor.next().unwrap().decode(&db).is_err());
+        drop(cursor);
+        assert_eq!(uuid, owned.uuid);
+        assert_eq!(1, owned.timestamp);
+        assert_eq!(Some("open"), owned.get("action"));
+        assert_eq!(vec![("user".to_owned(), "alice".to_owned()), ("action".to_owned(), "open".to_owned())],
+                   owned.values);
+    }
+
+    #[test]
+    fn parse_filter() {
+        let tmp_dir = TempDir::new("tdb_parse_filter").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["author", "name", "price"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 0, &["Asimov", "Foundation", "10"]).is_ok());
+        assert!(cons.add(&uuid, 1, &["Asimov", "I, Robot", ""]).is_ok());
+        assert!(cons.add(&uuid, 2, &["Herbert", "Dune", "12"]).is_ok());
+        assert!(cons.add(&uuid, 3, &["Le Guin", "Earthsea", ""]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+
+        let timestamps = |expr: &str| -> Vec<u64> {
+            let filter = EventFilter::parse(&db, expr).unwrap();
+            let mut cursor = db.cursor();
+            cursor.set_filter(&filter).unwrap();
+            cursor.get_trail(0).unwrap();
+            read_timestamps(&mut cursor)
+        };
+        assert_eq!(vec![0, 1], timestamps("author=Asimov"));
+        assert_eq!(vec![0, 1, 2], timestamps("author=Asimov author=Herbert"));
+        assert_eq!(vec![0], timestamps("author=Asimov & price!="));
+        assert_eq!(vec![1, 3], timestamps(" price= \t"));
+        assert_eq!(vec![2, 3], timestamps("author!=Asimov"));
+        assert_eq!(Vec::<u64>::new(), timestamps("author=Tolkien"));
+        assert_eq!(vec![0, 1, 2, 3], timestamps("author!=Tolkien"));
+        assert_eq!(vec![1, 2], timestamps("time=1..3"));
+        assert_eq!(vec![0, 3], timestamps("time=..1 time=3.."));
+        assert_eq!(vec![1, 3], timestamps(r#"name="I, Robot" author="Le Guin""#));
+        assert_eq!(vec![2], timestamps(r#"name="\x44une""#));
+        assert_eq!(vec![0, 2, 3], timestamps(r#"name!="I, Robot""#));
+
+        let names = tmp_dir.path().join("names");
+        std::fs::write(&names, "Dune\nEarthsea\n").unwrap();
+        let expr = format!("name=@{}", names.display());
+        assert_eq!(vec![2, 3], timestamps(&expr));
+        std::fs::write(&names, "Dune\n").unwrap();
+        assert_eq!(vec![0, 1, 3], timestamps(&format!("name!=@{}", names.display())));
+
+        let error = |expr: &str| {
+            let err = EventFilter::parse(&db, expr).err().unwrap();
+            (err.message().to_owned(), err.span())
+        };
+        assert_eq!(("unknown field `titel`".to_owned(), 14..19), error("author=Asimov titel=Dune"));
+        assert_eq!(("expected `=` in term".to_owned(), 0..6), error("author"));
+        assert_eq!(("expected a field name".to_owned(), 0..0), error("=Dune"));
+        assert_eq!(("expected a term before `&`".to_owned(), 0..1), error("& author=Asimov"));
+        assert_eq!(("expected a term before `&`".to_owned(), 16..17), error("author=Asimov & & name=Dune"));
+        assert_eq!(("expected a term after `&`".to_owned(), 14..15), error("author=Asimov &"));
+        assert_eq!(("empty filter expression".to_owned(), 0..2), error("  "));
+        assert_eq!(("invalid timestamp `x`".to_owned(), 8..9), error("time=1..x"));
+        assert_eq!(("time range is empty".to_owned(), 5..9), error("time=2..2"));
+        assert_eq!(("time ranges cannot be negated".to_owned(), 0..10), error("time!=1..2"));
+        assert_eq!("expected a file name after `@`", error("name=@").0);
+        assert!(error("name=@/nonexistent").0.starts_with("could not read `/nonexistent`"));
+        assert_eq!(("unterminated quote".to_owned(), 5..10), error(r#"name="Dune"#));
+        assert_eq!(("expected whitespace after a quoted value".to_owned(), 11..12), error(r#"name="Dune"x"#));
+        assert_eq!(("quotes must enclose the whole value".to_owned(), 5..11), error(r#"name=D"une""#));
+        assert_eq!(("field names cannot be quoted".to_owned(), 0..6), error(r#""name"=Dune"#));
+        assert_eq!(("invalid escape".to_owned(), 6..7), error(r#"name="\Dune""#));
+        assert_eq!(("expected two hex digits after `\\x`".to_owned(), 6..10), error(r#"name="\x+4une""#));
+        assert_eq!(("expected an item number and no value".to_owned(), 0..4), error("#1=a"));
+        assert_eq!("expected an item number and no value", error("#a=").0);
+        assert_eq!("unknown field `titel` at 14..19",
+                   EventFilter::parse(&db, "author=Asimov titel=Dune").err().unwrap().to_string());
+
+        // Lenient parsing takes unknown fields to be empty
+        let lenient = |expr: &str| -> Vec<u64> {
+            let filter = EventFilter::parse_lenient(&db, expr).unwrap();
+            let mut cursor = db.cursor();
+            cursor.set_filter(&filter).unwrap();
+            cursor.get_trail(0).unwrap();
+            read_timestamps(&mut cursor)
+        };
+        assert_eq!(vec![0, 1], lenient("author=Asimov titel=Dune"));
+        assert_eq!(vec![0, 1, 2, 3], lenient("titel="));
+        assert_eq!(vec![0, 1, 2, 3], lenient("titel!=Dune"));
+        assert_eq!(Vec::<u64>::new(), lenient("titel!="));
+        assert_eq!(vec![1, 3], lenient("price= & titel!=Dune"));
+        std::fs::write(&names, "Dune\n\n").unwrap();
+        assert_eq!(vec![0, 1, 2, 3], lenient(&format!("titel=@{}", names.display())));
+        std::fs::write(&names, "Dune\n").unwrap();
+        assert_eq!(Vec::<u64>::new(), lenient(&format!("titel=@{}", names.display())));
+        assert_eq!(vec![0, 1, 2, 3], lenient(&format!("titel!=@{}", names.display())));
+        assert_eq!("expected `=` in term", EventFilter::parse_lenient(&db, "titel").err().unwrap().message());
+    }
+
+    #[test]
+    fn filter_clauses() {
+        let tmp_dir = TempDir::new("tdb_filter_clauses").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["author", "price"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 0, &["Asimov", "10"]).is_ok());
+        assert!(cons.add(&uuid, 1, &["Herbert", ""]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        let author = db.get_field("author").unwrap();
+        let price = db.get_field("price").unwrap();
+
+        let expr = "author=Asimov price!= & time=5..10 time=20.. author!=Herbert";
+        let filter = EventFilter::parse(&db, expr).unwrap();
+        assert_eq!(2, filter.num_clauses());
+        assert_eq!(Ok(3), filter.num_terms(1));
+        assert_eq!(vec![Clause { terms: vec![Term::Item { item: db.get_item(author, "Asimov").unwrap(), negated: false },
+                                             Term::Item { item: db.get_item(price, "").unwrap(), negated: true }] },
+                        Clause { terms: vec![Term::TimeRange { start: 5, end: 10 },
+                                             Term::TimeRange { start: 20, end: u64::MAX },
+                                             Term::Item { item: db.get_item(author, "Herbert").unwrap(), negated: true }] }],
+                   filter.clauses());
+        assert_eq!(Err(ErrorKind::NoSuchItem), filter.term(0, 2).map_err(|e| e.kind()));
+        assert_eq!(Err(ErrorKind::NoSuchItem), filter.num_terms(2).map_err(|e| e.kind()));
+
+        // Display renders the parse syntax
+        assert_eq!(expr, filter.display(&db).to_string());
+        let parsed = EventFilter::parse(&db, &filter.display(&db).to_string()).unwrap();
+        assert_eq!(filter.clauses(), parsed.clauses());
+
+        let filter = EventFilter::parse(&db, "author=Tolkien time=..3").unwrap();
+        assert_eq!("#0= time=..3", filter.display(&db).to_string());
+        assert_eq!("", EventFilter::new().display(&db).to_string());
+        assert_eq!("<match all>", EventFilter::all().display(&db).to_string());
+        assert_eq!("<match none>", EventFilter::none().display(&db).to_string());
+        assert!(EventFilter::all().is_match_all());
+        assert!(EventFilter::none().is_match_none());
+        assert_eq!(vec![Clause::default()], EventFilter::all().clauses());
+
+        // Values the syntax would misread are quoted, so they parse back
+        let path = tmp_dir.path().join("quoted");
+        let mut cons = Constructor::new(&path, &["name"]).unwrap();
+        let names: Vec<&[u8]> = vec![b"Le Guin", b"a&b", b"&", b"x=y", b"@home", b"say \"hi\"", b"back\\slash",
+                                     b"tab\there", b"\xffbytes", "caf\u{e9}".as_bytes(), b""];
+        for (t, name) in names.iter().enumerate() {
+            assert!(cons.add_bytes(&uuid, t as u64, &[name]).is_ok());
+        }
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        let mut filter = EventFilter::new();
+        for (i, name) in names.iter().enumerate() {
+            let item = db.get_item(1, name).unwrap();
+            if i % 2 == 0 { filter.try_or(item) } else { filter.try_or_not(item) }.unwrap();
+        }
+        filter.try_and().unwrap().try_or(Item(0)).unwrap().try_or_not(Item(0)).unwrap();
+        let shown = filter.display(&db).to_string();
+        assert_eq!(r#"name="Le Guin" name!="a&b" name="&" name!="x=y" name="@home" name!="say \"hi\"" "#.to_owned() +
+                   r#"name="back\\slash" name!="tab\x09here" name="\xffbytes" name!=café name= & #0= #0!="#,
+                   shown);
+        assert_eq!(filter.clauses(), EventFilter::parse(&db, &shown).unwrap().clauses());
+    }
+
+    #[test]
+    fn filter_expr() {
+        let tmp_dir = TempDir::new("tdb_filter_expr").unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        let mut shards = vec![];
+        for (i, authors) in [["Asimov", "Herbert"], ["Herbert", "Le Guin"]].iter().enumerate() {
+            let path = tmp_dir.path().join(format!("shard{}", i));
+            let mut cons = Constructor::new(&path, &["author"]).unwrap();
+            for (t, author) in authors.iter().enumerate() {
+                assert!(cons.add(&uuid, t as u64, &[author]).is_ok());
+            }
+            assert!(cons.finalize().is_ok());
+            shards.push(Db::open(&path).unwrap());
+        }
+        let matching = |expr: &FilterExpr| -> Vec<Vec<u64>> {
+            shards.iter()
+                .map(|db| {
+                    let filter = expr.bind(db).unwrap();
+                    let mut cursor = db.cursor();
+                    cursor.set_filter(&filter).unwrap();
+                    cursor.get_trail(0).unwrap();
+                    read_timestamps(&mut cursor)
+                })
+                .collect()
+        };
+
+        // Asimov is only in the first shard
+        assert_eq!(vec![vec![0], vec![]], matching(&FilterExpr::equals("author", "Asimov")));
+        assert_eq!(vec![vec![1], vec![0, 1]], matching(&FilterExpr::not_equals("author", "Asimov")));
+        assert_eq!(vec![vec![0, 1], vec![0]],
+                   matching(&FilterExpr::equals("author", "Asimov").or(FilterExpr::equals("author", "Herbert"))));
+        // The missing field and value make always matching clauses
+        let expr = FilterExpr::not_equals("title", "Dune")
+            .and(!FilterExpr::equals("author", "Asimov"))
+            .and(FilterExpr::time_range(0, 2))
+            .and(FilterExpr::not_equals("author", "Herbert").or(FilterExpr::equals("author", "Le Guin")));
+        assert_eq!(vec![vec![], vec![1]], matching(&expr));
+        assert_eq!(2, expr.bind(&shards[1]).unwrap().num_clauses());
+        assert_eq!(vec![vec![0], vec![0]], matching(&!FilterExpr::time_range(1, 5)));
+        // A missing field is always empty
+        assert_eq!(vec![vec![0, 1], vec![0, 1]], matching(&FilterExpr::equals("title", "")));
+        assert_eq!(vec![Vec::<u64>::new(), vec![]], matching(&FilterExpr::not_equals("title", "")));
+        assert_eq!(vec![Vec::<u64>::new(), vec![]], matching(&FilterExpr::equals("title", "Dune")));
+        assert!(FilterExpr::equals("title", "").bind(&shards[0]).unwrap().is_match_all());
+        assert_eq!(vec![vec![0, 1], vec![0, 1]], matching(&FilterExpr::And(vec![])));
+        assert_eq!(vec![Vec::<u64>::new(), vec![]], matching(&FilterExpr::Or(vec![])));
+        // Expressions that are not in conjunctive normal form
+        let expr = FilterExpr::equals("author", "Asimov").and(FilterExpr::time_range(0, 1))
+            .or(FilterExpr::equals("author", "Le Guin"));
+        assert_eq!(vec![vec![0], vec![1]], matching(&expr));
+        let expr = !FilterExpr::equals("author", "Herbert").and(FilterExpr::time_range(1, 2));
+        assert_eq!(vec![vec![0], vec![0, 1]], matching(&expr));
+
+        let db = &shards[0];
+        assert!(FilterExpr::not_equals("author", "Tolkien").bind(db).unwrap().is_match_all());
+        assert_eq!(Some(FilterExprError::TooLarge { limit: 1 }),
+                   FilterExpr::equals("a", "b").and(FilterExpr::equals("c", "d")).negate().bind_with_limit(db, 1).err());
+        assert_eq!(Some(FilterExprError::InvalidRange { start: 2, end: 2 }),
+                   FilterExpr::time_range(2, 2).bind(db).err());
+    }
+
+    #[test]
+    fn filter_cnf() {
+        let a = || FilterExpr::equals("author", "Asimov");
+        let b = || FilterExpr::equals("author", "Herbert");
+        let c = || FilterExpr::time_range(1, 2);
+        let cnf = |clauses: Vec<Vec<FilterExpr>>| FilterExpr::And(clauses.into_iter().map(FilterExpr::Or).collect());
+
+        assert_eq!(cnf(vec![vec![a(), c()], vec![b(), c()]]), a().and(b()).or(c()).to_cnf().unwrap());
+        assert_eq!(cnf(vec![vec![!a(), !b()]]), (!a().and(b())).to_cnf().unwrap());
+        assert_eq!(cnf(vec![vec![a()], vec![b()]]), (!(!a()).or(!b())).to_cnf().unwrap());
+        // Repeated terms and clauses
+        assert_eq!(cnf(vec![vec![a(), b()]]), a().or(b()).or(a()).and(b().or(a())).to_cnf().unwrap());
+        // Tautologies and implied clauses
+        assert_eq!(cnf(vec![]), a().or(!a()).to_cnf().unwrap());
+        assert_eq!(cnf(vec![vec![c()]]), c().and(a().or(!a())).to_cnf().unwrap());
+        assert_eq!(cnf(vec![vec![a()]]), a().and(a().or(b())).to_cnf().unwrap());
+        assert_eq!(cnf(vec![vec![]]), FilterExpr::Or(vec![]).to_cnf().unwrap());
+
+        // Distributing doubles the size for every OR of two ANDs
+        let expr = FilterExpr::Or((0..20)
+                                      .map(|i| FilterExpr::equals("a", i.to_string()).and(FilterExpr::equals("b", i.to_string())))
+                                      .collect());
+        assert_eq!(Some(FilterExprError::TooLarge { limit: MAX_CNF_TERMS }), expr.to_cnf().err());
+        assert_eq!(Some(FilterExprError::TooLarge { limit: 100 }), expr.to_cnf_with_limit(100).err());
+        // Large products are rejected before they are built
+        let and = |field: &str| FilterExpr::And((0..3000).map(|i| FilterExpr::equals(field, i.to_string())).collect());
+        assert_eq!(Some(FilterExprError::TooLarge { limit: MAX_CNF_TERMS }), and("a").or(and("b")).to_cnf().err());
+
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn filter_expr_serde() {
+        extern crate serde_json;
+
+        let expr = FilterExpr::equals("author", "Asimov").or(FilterExpr::time_range(1, 2)).and(!FilterExpr::equals("price", ""));
+        let json = serde_json::to_string(&expr).unwrap();
+        assert_eq!(r#"{"and":[{"or":[{"equals":{"field":"author","value":"Asimov"}},{"time_range":{"start":1,"end":2}}]},{"not":{"equals":{"field":"price","value":""}}}]}"#,
+                   json);
+        assert_eq!(expr, serde_json::from_str(&json).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize() {
+        use self::serde_derive::Deserialize;
+        use super::EventDeserializer;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Kind {
+            #[serde(rename = "view")]
+            View,
+            #[serde(rename = "click")]
+            Click,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct PageView<'a> {
+            time: u64,
+            url: &'a str,
+            kind: Kind,
+            duration: Option<u32>,
+        }
+
+        let tmp_dir = TempDir::new("tdb_deserialize").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["url", "kind", "duration"]).unwrap();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        assert!(cons.add(&uuid, 1, &["/", "view", "30"]).is_ok());
+        assert!(cons.add(&uuid, 2, &["/about", "click", ""]).is_ok());
+        assert!(cons.add(&uuid, 3, &["/about", "view", "long"]).is_ok());
+        assert!(cons.add(&uuid, 4, &["/about", "scroll", "1"]).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+
+        let mut cursor = db.cursor();
+        cursor.get_trail(0).unwrap();
+        let views: Vec<_> = cursor.deserialize::<PageView>().collect();
+        assert_eq!(4, views.len());
+        assert_eq!(Ok(PageView { time: 1, url: "/", kind: Kind::View, duration: Some(30) }), views[0]);
+        assert_eq!(Ok(PageView { time: 2, url: "/about", kind: Kind::Click, duration: None }), views[1]);
+
+        let err = views[2].as_ref().unwrap_err();
+        assert_eq!(Some("duration"), err.field());
+        assert_eq!("invalid value `long`: invalid digit found in string, field `duration`", err.to_string());
+        assert_eq!(Some("kind"), views[3].as_ref().unwrap_err().field());
+
+        #[derive(Debug, Deserialize)]
+        struct Missing {
+            #[allow(dead_code)]
+            referrer: String,
+        }
+        cursor.get_trail(0).unwrap();
+        let err = cursor.deserialize::<Missing>().next().unwrap().unwrap_err();
+        assert_eq!(Some("referrer"), err.field());
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Renamed {
+            ts: i64,
+        }
+        cursor.get_trail(0).unwrap();
+        let record = cursor.next().unwrap().decode(&db).unwrap();
+        let renamed: Renamed = serde::Deserialize::deserialize(EventDeserializer::new(&record).timestamp_key("ts")).unwrap();
+        assert_eq!(Renamed { ts: 1 }, renamed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed_constructor() {
+        use self::serde_derive::{Deserialize, Serialize};
+        use std::collections::{BTreeMap, HashMap};
+        use super::TypedConstructor;
+
+        #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+        enum Kind {
+            View,
+            Click,
+        }
+
+        // The UUID is not part of the event when reading it back
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct PageView<'a> {
+            #[serde(rename = "uuid", default)]
+            user: [u8; 16],
+            time: u64,
+            url: &'a str,
+            kind: Kind,
+            duration: Option<u32>,
+            bot: bool,
+        }
+
+        let tmp_dir = TempDir::new("tdb_typed").unwrap();
+        let path = tmp_dir.path().join("test");
+        let user = *uuid::Uuid::new_v4().as_bytes();
+        let views = vec![PageView { user, time: 1, url: "/", kind: Kind::View, duration: Some(30), bot: false },
+                         PageView { user, time: 2, url: "/a", kind: Kind::Click, duration: None, bot: true }];
+        let mut cons = TypedConstructor::new(&path);
+        for view in &views {
+            assert!(cons.add(view).is_ok());
+        }
+        assert_eq!(&["url", "kind", "duration", "bot"], cons.fields());
+        assert!(cons.finalize().is_ok());
+
+        let db = Db::open(&path).unwrap();
+        let mut cursor = db.cursor();
+        cursor.get_trail(0).unwrap();
+        let read: Vec<PageView> = cursor.deserialize::<PageView>().map(Result::unwrap).collect();
+        assert_eq!(user, *db.get_uuid(0).unwrap());
+        assert_eq!(views.iter().map(|v| PageView { user: [0; 16], ..*v }).collect::<Vec<_>>(), read);
+
+        // Maps work too; UUIDs may be hex strings
+        let path = tmp_dir.path().join("map");
+        let mut cons = TypedConstructor::new(&path);
+        let mut record = BTreeMap::new();
+        record.insert("a", "1");
+        record.insert("time", "5");
+        assert_eq!(Some("uuid"), cons.add(&record).unwrap_err().field());
+        record.insert("uuid", "not a uuid");
+        assert_eq!(Some("uuid"), cons.add(&record).unwrap_err().field());
+        // Strings are always hex, even when they are 16 bytes long
+        record.insert("uuid", "0123456789abcdef");
+        assert_eq!(Some("uuid"), cons.add(&record).unwrap_err().field());
+        record.insert("uuid", "0123456789abcdef-0123456789ABCDEF");
+        assert!(cons.add(&record).is_ok());
+        record.insert("b", "2");
+        assert_eq!(Some("b"), cons.add(&record).unwrap_err().field());
+        record.remove("b");
+        record.remove("a");
+        record.insert("time", "6");
+        assert!(cons.add(&record).is_ok());
+        record.insert("time", "x");
+        assert_eq!(Some("time"), cons.add(&record).unwrap_err().field());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        assert_eq!(2, db.num_events());
+        assert_eq!(0x0123456789abcdef0123456789abcdef_u128.to_be_bytes(), *db.get_uuid(0).unwrap());
+        assert_eq!(vec!["1"], db.lexicon(1).unwrap());
+
+        // Keys are matched by name, whatever order a map has them in;
+        // missing ones are empty
+        let path = tmp_dir.path().join("hash_map");
+        let mut cons = TypedConstructor::new(&path);
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        let mut record: HashMap<&str, &str> = keys.iter().map(|key| (key.as_str(), key.as_str())).collect();
+        record.insert("uuid", "0123456789abcdef0123456789abcdef");
+        record.insert("time", "1");
+        assert!(cons.add(&record).is_ok());
+        let fields = cons.fields().to_vec();
+        // A new map has a new hash seed and so most likely another order
+        let mut record: HashMap<&str, &str> = record.into_iter().collect();
+        record.insert("time", "2");
+        record.remove("k3");
+        assert!(cons.add(&record).is_ok());
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+        let mut cursor = db.cursor();
+        cursor.get_trail(0).unwrap();
+        let mut events = vec![];
+        while let Some(event) = cursor.next() {
+            let values: Vec<(&str, &str)> = event.items
+                .iter()
+                .map(|&item| (db.get_field_name(item.field()).unwrap(), db.get_item_value(item).unwrap().unwrap_or("")))
+                .collect();
+            events.push(values);
+        }
+        assert_eq!(2, events.len());
+        for (i, key) in fields.iter().enumerate() {
+            assert_eq!((key.as_str(), key.as_str()), events[0][i]);
+            let value = if key == "k3" { "" } else { key.as_str() };
+            assert_eq!((key.as_str(), value), events[1][i]);
+        }
+
+        // The fields are those of the first record, skipped ones are
+        // not added later
+        #[derive(Serialize)]
+        struct Note {
+            uuid: [u8; 16],
+            time: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            note: Option<&'static str>,
+            url: &'static str,
+        }
+        let path = tmp_dir.path().join("skipped");
+        let mut cons = TypedConstructor::new(&path);
+        assert!(cons.fields().is_empty());
+        assert!(cons.add(&Note { uuid: user, time: 1, note: None, url: "/" }).is_ok());
+        assert_eq!(&["url"], cons.fields());
+        assert_eq!(Some("note"), cons.add(&Note { uuid: user, time: 2, note: Some("x"), url: "/" }).unwrap_err().field());
+
+        // Sequences of numbers are stored as bytes
+        #[derive(Serialize)]
+        struct Digest {
+            uuid: [u8; 16],
+            time: u64,
+            digest: Vec<u32>,
+        }
+        let path = tmp_dir.path().join("bytes");
+        let mut cons = TypedConstructor::new(&path);
+        assert!(cons.add(&Digest { uuid: user, time: 1, digest: vec![104, 105] }).is_ok());
+        assert_eq!(Some("digest"), cons.add(&Digest { uuid: user, time: 2, digest: vec![1, 300] }).unwrap_err().field());
+        assert!(cons.finalize().is_ok());
+        assert_eq!(vec!["hi"], Db::open(&path).unwrap().lexicon(1).unwrap());
+
+        // A UUID of bytes is used as is and must have 16 of them
+        #[derive(Serialize)]
+        struct RawUuid {
+            uuid: Vec<u8>,
+            time: u64,
+        }
+        let path = tmp_dir.path().join("raw_uuid");
+        let mut cons = TypedConstructor::new(&path);
+        assert_eq!(Some("uuid"), cons.add(&RawUuid { uuid: vec![1; 15], time: 1 }).unwrap_err().field());
+        assert!(cons.add(&RawUuid { uuid: b"0123456789abcdef".to_vec(), time: 1 }).is_ok());
+        assert!(cons.finalize().is_ok());
+        assert_eq!(b"0123456789abcdef", Db::open(&path).unwrap().get_uuid(0).unwrap());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_trails() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let tmp_dir = TempDir::new("tdb_par").unwrap();
+        let path = tmp_dir.path().join("test");
+        let mut cons = Constructor::new(&path, &["field"]).unwrap();
+        for i in 0..100u64 {
+            let uuid = *uuid::Uuid::new_v4().as_bytes();
+            for j in 0..i % 7 + 1 {
+                assert!(cons.add(&uuid, j, &["x"]).is_ok());
+            }
+        }
+        assert!(cons.finalize().is_ok());
+        let db = Db::open(&path).unwrap();
+
+        let events = AtomicUsize::new(0);
+        db.par_for_each_trail(|_, cursor| {
+            events.fetch_add(cursor.len() as usize, Ordering::Relaxed);
+        });
+        assert_eq!(db.num_events() as usize, events.into_inner());
+
+        // Every trail is visited exactly once, whatever the chunk size
+        for &chunk_size in &[1, 3, 64, 1000] {
+            let mut ids = db.par_trails()
+                .chunk_size(chunk_size)
+                .fold(Vec::new,
+                      |mut ids, id, _| {
+                          ids.push(id);
+                          ids
+                      },
+                      |mut a, b| {
+                          a.extend(b);
+                          a
+                      });
+            ids.sort();
+            assert_eq!((0..100).collect::<Vec<_>>(), ids);
+        }
+
+        let longest = db.par_trails().fold(|| 0, |max, _, cursor| max.max(cursor.len()), |a, b| a.max(b));
+        assert_eq!(7, longest);
     }
 }
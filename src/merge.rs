@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use {Constructor, ConstructorOptions, Db, Error, Format};
+
+/// Merge `inputs` into a new TrailDB at `output`.
+///
+/// The new database has the union of the fields of the inputs, in the
+/// order they first appear. Events of an input that lacks a field get
+/// an empty value for it. Filters set on the inputs apply, so only the
+/// events they match are merged.
+///
+/// ```no_run
+/// # use traildb::{merge, Db};
+/// let days = vec![Db::open("2024-01-01").unwrap(), Db::open("2024-01-02").unwrap()];
+/// merge(&days, "2024-01").unwrap();
+/// ```
+pub fn merge<P: AsRef<Path>>(inputs: &[Db], output: P) -> Result<(), Error> {
+    merge_with_options(inputs, output, &ConstructorOptions::new())
+}
+
+/// Merge `inputs` into a new TrailDB at `output`, created with the
+/// given options.
+pub fn merge_with_options<P: AsRef<Path>>(inputs: &[Db],
+                                          output: P,
+                                          options: &ConstructorOptions)
+                                          -> Result<(), Error> {
+    let mut fields: Vec<&str> = vec![];
+    for db in inputs {
+        for name in field_names(db) {
+            if !fields.contains(&name) {
+                fields.push(name);
+            }
+        }
+    }
+    let mut cons = options.open(output.as_ref(), &fields)?;
+    for db in inputs {
+        // TrailDB leaves out the values of only-diff events that did not
+        // change, so those inputs need the values carried over
+        if field_names(db).eq(fields.iter().cloned()) && !db.only_diff_items()? {
+            // Same fields in the same order: let TrailDB copy the lexicons.
+            cons.append(db)?;
+        } else {
+            append_mapped(&mut cons, db, &fields)?;
+        }
+    }
+    cons.finalize()
+}
+
+/// Copy the TrailDB at `path_in` to `path_out` in `format`. A package
+/// is written to `<path_out>.tdb`.
+///
+/// Fails with `ErrorKind::InvalidOptionValue` when asking for
+/// `Format::Package` from a TrailDB built without libarchive.
+///
+/// ```no_run
+/// # use traildb::{convert, Format};
+/// convert("events", "dist/events", Format::Package).unwrap();
+/// ```
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(path_in: P, path_out: Q, format: Format) -> Result<(), Error> {
+    let db = Db::open(path_in)?;
+    let mut options = ConstructorOptions::new();
+    options.output_format(format);
+    merge_with_options(&[db], path_out, &options)
+}
+
+/// The names of the fields of `db`, without the timestamp.
+fn field_names(db: &Db) -> impl Iterator<Item = &str> {
+    (1..db.num_fields() as u32).map(move |field| db.get_field_name(field).unwrap_or_default())
+}
+
+/// Add the events of `db` to `cons`, moving every value to the field
+/// of the same name in `fields`.
+///
+/// Values are carried over from the previous event of the trail, which
+/// fills in the fields left out with `DbOption::OnlyDiffItems`. Without
+/// it every event has all fields of `db`, so nothing is carried over.
+fn append_mapped(cons: &mut Constructor, db: &Db, fields: &[&str]) -> Result<(), Error> {
+    let field_map: Vec<usize> = field_names(db)
+        .map(|name| fields.iter().position(|&f| f == name).expect("field missing from the merged fields"))
+        .collect();
+    let mut trails = db.trails();
+    while let Some((id, cursor)) = trails.next_trail() {
+        let uuid = *db.get_uuid(id).expect("trail id out of range");
+        let mut values: Vec<&[u8]> = vec![b""; fields.len()];
+        while let Some(event) = cursor.next() {
+            for &item in event.items {
+                values[field_map[item.field() as usize - 1]] = db.get_item_bytes(item).unwrap_or_default();
+            }
+            cons.add_bytes(&uuid, event.timestamp, &values)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::cmp;
+use std::ops::Range;
+
+use rayon;
+use rayon::prelude::*;
+
+use {Cursor, Db, TrailId};
+
+/// Chunks handed out per worker thread when no chunk size is given.
+/// More chunks than threads keeps workers busy when trail lengths vary.
+const CHUNKS_PER_THREAD: u64 = 8;
+
+impl Db {
+    /// Scan the trails of the database in parallel on the rayon thread
+    /// pool.
+    ///
+    /// The trail id space is split into chunks and every worker reuses
+    /// a single `Cursor` for all trails it visits.
+    pub fn par_trails(&self) -> ParTrails<'_> {
+        ParTrails {
+            db: self,
+            ids: 0..self.num_trails(),
+            chunk_size: None,
+        }
+    }
+
+    /// Call `f` for every trail of the database, in parallel. This is a
+    /// shorthand for `db.par_trails().for_each(f)`.
+    pub fn par_for_each_trail<F>(&self, f: F)
+        where F: Fn(TrailId, &mut Cursor) + Sync + Send
+    {
+        self.par_trails().for_each(f)
+    }
+}
+
+/// A parallel scan over the trails of a `Db`, created by
+/// `Db::par_trails`.
+///
+/// The callbacks receive the trail id and a cursor already positioned
+/// at the start of that trail.
+pub struct ParTrails<'a> {
+    db: &'a Db,
+    ids: Range<TrailId>,
+    chunk_size: Option<u64>,
+}
+
+impl<'a> ParTrails<'a> {
+    /// Number of consecutive trails a worker takes at a time. By default
+    /// the trails are split into a few chunks per thread.
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = Some(cmp::max(chunk_size, 1));
+        self
+    }
+
+    /// Call `f` for every trail.
+    pub fn for_each<F>(self, f: F)
+        where F: Fn(TrailId, &mut Cursor) + Sync + Send
+    {
+        self.fold(|| (), |(), id, cursor| f(id, cursor), |(), ()| ())
+    }
+
+    /// Fold every trail into an accumulator and combine the
+    /// accumulators of the workers.
+    ///
+    /// `identity` creates an empty accumulator, `fold` adds one trail
+    /// to an accumulator and `reduce` merges two accumulators. Trails
+    /// are not visited in order, so `reduce` should be associative.
+    ///
+    /// ```no_run
+    /// # use traildb::Db;
+    /// let db = Db::open("events.tdb").unwrap();
+    /// let longest = db.par_trails().fold(|| 0,
+    ///                                    |max, _, cursor| max.max(cursor.len()),
+    ///                                    |a, b| a.max(b));
+    /// ```
+    pub fn fold<T, ID, F, R>(self, identity: ID, fold: F, reduce: R) -> T
+        where T: Send,
+              ID: Fn() -> T + Sync + Send,
+              F: Fn(T, TrailId, &mut Cursor) -> T + Sync + Send,
+              R: Fn(T, T) -> T + Sync + Send
+    {
+        let db = self.db;
+        let start = self.ids.start;
+        let end = self.ids.end;
+        if start >= end {
+            return identity();
+        }
+        let chunk_size = self.chunk_size.unwrap_or_else(|| {
+            let chunks = rayon::current_num_threads() as u64 * CHUNKS_PER_THREAD;
+            cmp::max((end - start) / chunks, 1)
+        });
+        let num_chunks = (end - start).div_ceil(chunk_size);
+
+        (0..num_chunks)
+            .into_par_iter()
+            .fold(|| (db.cursor(), identity()), |(mut cursor, mut acc), chunk| {
+                let chunk_start = start + chunk * chunk_size;
+                let chunk_end = cmp::min(chunk_start + chunk_size, end);
+                for id in chunk_start..chunk_end {
+                    cursor.get_trail(id).expect("trail id out of range");
+                    acc = fold(acc, id, &mut cursor);
+                }
+                (cursor, acc)
+            })
+            .map(|(_, acc)| acc)
+            .reduce(&identity, &reduce)
+    }
+}
@@ -0,0 +1,368 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::str;
+
+use {Db, EventFilter, Field, Item, Timestamp};
+
+/// An error in a filter expression given to `EventFilter::parse`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct FilterParseError {
+    message: String,
+    span: Range<usize>,
+}
+
+impl FilterParseError {
+    fn new<M: Into<String>>(message: M, span: Range<usize>) -> FilterParseError {
+        FilterParseError {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// What is wrong with the expression.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The byte range of the expression the error refers to.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+}
+
+impl fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}..{}", self.message, self.span.start, self.span.end)
+    }
+}
+
+impl error::Error for FilterParseError {}
+
+/// The field name used for time range terms.
+const TIME_FIELD: &str = "time";
+
+/// A term of a clause, before it is added to the filter.
+enum Term {
+    Item(Item, bool),
+    TimeRange(Timestamp, Timestamp),
+}
+
+impl EventFilter {
+    /// Parse a filter expression in the syntax of the `tdb` command line
+    /// tool.
+    ///
+    /// Terms are separated by whitespace and are ORed together. A `&`
+    /// starts a new clause, clauses are ANDed together. A term is one of
+    ///
+    /// - `field=value`: the field has the value,
+    /// - `field!=value`: the field does not have the value,
+    /// - `field=`: the field is empty,
+    /// - `field=@path`: the field has one of the values listed in the
+    ///   file at `path`, one per line,
+    /// - `time=start..end`: the timestamp is in `start..end`. Either end
+    ///   of the range may be left out,
+    /// - `#item=` or `#item!=`: the event has (or does not have) the item
+    ///   with that number. `EventFilter::display` writes items that are
+    ///   not in `db` like this.
+    ///
+    /// A value in double quotes, such as `name="I, Robot"`, can have
+    /// whitespace, `&` and a leading `@`. In quotes, `\"` and `\\` stand
+    /// for `"` and `\`, and `\xNN` for the byte with hex value `NN`.
+    ///
+    /// Values that are not in the lexicon of `db` never match, so their
+    /// negation always matches. Fields that are not in `db` are an
+    /// error, see `parse_lenient`.
+    ///
+    /// ```no_run
+    /// # use traildb::{Db, EventFilter};
+    /// let db = Db::open("books.tdb").unwrap();
+    /// let filter = EventFilter::parse(&db, "author=Asimov & name=Foundation name=@names.txt & price!=").unwrap();
+    /// ```
+    pub fn parse(db: &Db, expr: &str) -> Result<EventFilter, FilterParseError> {
+        parse(db, expr, false)
+    }
+
+    /// Like `parse`, but a field that is not in `db` is taken to be
+    /// empty in every event, as it is in a merge of databases with
+    /// different fields: `field=` matches every event, `field=value`
+    /// none, and their negations the opposite.
+    ///
+    /// The `tdb` tool of the C library also accepts unknown fields, but
+    /// its terms on them never match, not even `field=`.
+    pub fn parse_lenient(db: &Db, expr: &str) -> Result<EventFilter, FilterParseError> {
+        parse(db, expr, true)
+    }
+}
+
+fn parse(db: &Db, expr: &str, lenient: bool) -> Result<EventFilter, FilterParseError> {
+    let mut clauses: Vec<Vec<Term>> = vec![vec![]];
+    let mut and_span = None;
+    for (token, start) in tokens(expr)? {
+        let span = start..start + token.len();
+        if token == "&" {
+            if clauses.last().unwrap().is_empty() {
+                return Err(FilterParseError::new("expected a term before `&`", span));
+            }
+            clauses.push(vec![]);
+            and_span = Some(span);
+        } else {
+            let clause = clauses.last_mut().unwrap();
+            parse_term(db, token, start, lenient, clause)?;
+            and_span = None;
+        }
+    }
+    if let Some(span) = and_span {
+        return Err(FilterParseError::new("expected a term after `&`", span));
+    }
+    if clauses[0].is_empty() {
+        return Err(FilterParseError::new("empty filter expression", 0..expr.len()));
+    }
+
+    let mut filter = EventFilter::new();
+    for (i, clause) in clauses.into_iter().enumerate() {
+        if i > 0 {
+            filter.try_and().expect("a new filter takes clauses");
+        }
+        for term in clause {
+            let added = match term {
+                Term::Item(item, false) => filter.try_or(item),
+                Term::Item(item, true) => filter.try_or_not(item),
+                Term::TimeRange(start, end) => filter.try_time_range(start, end),
+            };
+            added.expect("a new filter takes terms, time ranges are not empty");
+        }
+    }
+    Ok(filter)
+}
+
+/// Split an expression into whitespace separated tokens and their byte
+/// offsets. Whitespace in double quotes does not split tokens.
+fn tokens(expr: &str) -> Result<Vec<(&str, usize)>, FilterParseError> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in expr.char_indices() {
+        if quote.is_some() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quote = None;
+            }
+            continue;
+        }
+        if c == '"' {
+            quote = Some(i);
+        }
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((&expr[s..i], s));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(q) = quote {
+        return Err(FilterParseError::new("unterminated quote", q..expr.len()));
+    }
+    if let Some(s) = start {
+        tokens.push((&expr[s..], s));
+    }
+    Ok(tokens)
+}
+
+fn parse_term(db: &Db,
+              token: &str,
+              start: usize,
+              lenient: bool,
+              clause: &mut Vec<Term>)
+              -> Result<(), FilterParseError> {
+    let eq = match token.find('=') {
+        Some(eq) => eq,
+        None => return Err(FilterParseError::new("expected `=` in term", start..start + token.len())),
+    };
+    let negated = token[..eq].ends_with('!');
+    let name = if negated { &token[..eq - 1] } else { &token[..eq] };
+    let name_span = start..start + name.len();
+    let value = &token[eq + 1..];
+    let value_span = start + eq + 1..start + token.len();
+
+    if name.is_empty() {
+        return Err(FilterParseError::new("expected a field name", name_span));
+    }
+    if name.contains('"') {
+        return Err(FilterParseError::new("field names cannot be quoted", name_span));
+    }
+    if let Some(number) = name.strip_prefix('#') {
+        return match number.parse() {
+            Ok(item) if value.is_empty() => {
+                clause.push(Term::Item(Item(item), negated));
+                Ok(())
+            }
+            _ => Err(FilterParseError::new("expected an item number and no value", start..start + token.len())),
+        };
+    }
+    if name == TIME_FIELD {
+        if negated {
+            return Err(FilterParseError::new("time ranges cannot be negated", start..start + token.len()));
+        }
+        let (range_start, range_end) = parse_time_range(value, value_span)?;
+        clause.push(Term::TimeRange(range_start, range_end));
+        return Ok(());
+    }
+    let field = match db.get_field(name) {
+        Ok(field) => Some(field),
+        Err(_) if lenient => None,
+        Err(_) => return Err(FilterParseError::new(format!("unknown field `{}`", name), name_span)),
+    };
+
+    let (quoted, contents);
+    let values = match value.strip_prefix('@') {
+        _ if value.starts_with('"') => {
+            quoted = unquote(value, value_span)?;
+            vec![&quoted[..]]
+        }
+        _ if value.contains('"') => {
+            return Err(FilterParseError::new("quotes must enclose the whole value", value_span));
+        }
+        Some(path) => {
+            let path_span = value_span.start + 1..value_span.end;
+            if path.is_empty() {
+                return Err(FilterParseError::new("expected a file name after `@`", path_span));
+            }
+            contents = fs::read(path)
+                .map_err(|e| FilterParseError::new(format!("could not read `{}`: {}", path, e), path_span.clone()))?;
+            let values = lines(&contents);
+            if negated && values.len() != 1 {
+                let message = "a negated value list must have exactly one value";
+                return Err(FilterParseError::new(message, path_span));
+            }
+            values
+        }
+        None => vec![value.as_bytes()],
+    };
+    match field {
+        Some(field) => {
+            for value in values {
+                clause.push(Term::Item(item(db, field, value), negated));
+            }
+        }
+        // No event has the item 0, so only its negation matches
+        None => clause.push(Term::Item(Item(0), values.contains(&&b""[..]) != negated)),
+    }
+    Ok(())
+}
+
+/// The bytes of a value in double quotes.
+fn unquote(value: &str, span: Range<usize>) -> Result<Vec<u8>, FilterParseError> {
+    let mut bytes = vec![];
+    let mut chars = value.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' if i + 1 == value.len() => return Ok(bytes),
+            '"' => {
+                let message = "expected whitespace after a quoted value";
+                return Err(FilterParseError::new(message, span.start + i + 1..span.end));
+            }
+            '\\' => {
+                match chars.next() {
+                    Some((_, '"')) => bytes.push(b'"'),
+                    Some((_, '\\')) => bytes.push(b'\\'),
+                    Some((_, 'x')) => {
+                        let hex = value.get(i + 2..i + 4).filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()));
+                        match hex {
+                            Some(hex) => bytes.push(u8::from_str_radix(hex, 16).unwrap()),
+                            None => {
+                                let end = (span.start + i + 4).min(span.end);
+                                return Err(FilterParseError::new("expected two hex digits after `\\x`", span.start + i..end));
+                            }
+                        }
+                        chars.next();
+                        chars.next();
+                    }
+                    _ => return Err(FilterParseError::new("invalid escape", span.start + i..span.start + i + 1)),
+                }
+            }
+            c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Err(FilterParseError::new("unterminated quote", span))
+}
+
+/// Write `value` so that `EventFilter::parse` reads it back: as is if
+/// it can be, otherwise in double quotes.
+pub(crate) fn write_value(f: &mut fmt::Formatter, value: &[u8]) -> fmt::Result {
+    let plain = str::from_utf8(value).ok().filter(|text| {
+        !text.starts_with('@') &&
+        !text.chars().any(|c| c.is_whitespace() || c.is_control() || "&=\"\\".contains(c))
+    });
+    if let Some(text) = plain {
+        return f.write_str(text);
+    }
+    f.write_str("\"")?;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (valid, invalid) = match str::from_utf8(rest) {
+            Ok(_) => (rest.len(), 0),
+            Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(rest.len() - e.valid_up_to())),
+        };
+        for c in str::from_utf8(&rest[..valid]).unwrap().chars() {
+            match c {
+                '"' | '\\' => write!(f, "\\{}", c)?,
+                c if c.is_control() => {
+                    for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                        write!(f, "\\x{:02x}", byte)?;
+                    }
+                }
+                c => write!(f, "{}", c)?,
+            }
+        }
+        for byte in &rest[valid..valid + invalid] {
+            write!(f, "\\x{:02x}", byte)?;
+        }
+        rest = &rest[valid + invalid..];
+    }
+    f.write_str("\"")
+}
+
+/// The item of a value, or an item that is never in an event if the
+/// value is not in the lexicon.
+fn item(db: &Db, field: Field, value: &[u8]) -> Item {
+    db.get_item(field, value).unwrap_or(Item(0))
+}
+
+/// Split the contents of a value list into lines. A final newline does
+/// not start another value.
+fn lines(contents: &[u8]) -> Vec<&[u8]> {
+    let contents = contents.strip_suffix(b"\n").unwrap_or(contents);
+    contents.split(|&c| c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect()
+}
+
+fn parse_time_range(value: &str, span: Range<usize>) -> Result<(Timestamp, Timestamp), FilterParseError> {
+    let sep = match value.find("..") {
+        Some(sep) => sep,
+        None => return Err(FilterParseError::new("expected a time range `start..end`", span)),
+    };
+    let parse = |s: &str, offset: usize, default: Timestamp| {
+        if s.is_empty() {
+            return Ok(default);
+        }
+        s.parse().map_err(|_| {
+            let span = span.start + offset..span.start + offset + s.len();
+            FilterParseError::new(format!("invalid timestamp `{}`", s), span)
+        })
+    };
+    let start = parse(&value[..sep], 0, 0)?;
+    let end = parse(&value[sep + 2..], sep + 2, Timestamp::MAX)?;
+    if start >= end {
+        return Err(FilterParseError::new("time range is empty", span));
+    }
+    Ok((start, end))
+}
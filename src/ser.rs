@@ -0,0 +1,670 @@
+use std::error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use serde::ser::{self, Impossible, Serialize};
+
+use {Constructor, ConstructorOptions, Error, Timestamp, Uuid, TIMESTAMP_KEY};
+
+/// The key the UUID of an event is serialized from, unless another one
+/// is set with `TypedConstructor::uuid_key`.
+pub const UUID_KEY: &str = "uuid";
+
+/// An error from adding a record to a `TypedConstructor`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SerializeError {
+    /// The record could not be turned into an event.
+    Invalid {
+        message: String,
+        field: Option<String>,
+    },
+    /// TrailDB rejected the event.
+    Tdb(Error),
+}
+
+impl SerializeError {
+    fn invalid<M: Into<String>>(message: M) -> SerializeError {
+        SerializeError::Invalid {
+            message: message.into(),
+            field: None,
+        }
+    }
+
+    /// The field that could not be serialized, if known.
+    pub fn field(&self) -> Option<&str> {
+        match *self {
+            SerializeError::Invalid { ref field, .. } => field.as_deref(),
+            SerializeError::Tdb(ref err) => err.field(),
+        }
+    }
+
+    fn in_field(self, name: &str) -> SerializeError {
+        match self {
+            SerializeError::Invalid { message, field: None } => {
+                SerializeError::Invalid {
+                    message,
+                    field: Some(name.to_owned()),
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+impl From<Error> for SerializeError {
+    fn from(err: Error) -> SerializeError {
+        SerializeError::Tdb(err)
+    }
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerializeError::Invalid { ref message, ref field } => {
+                write!(f, "{}", message)?;
+                if let Some(ref field) = *field {
+                    write!(f, ", field `{}`", field)?;
+                }
+                Ok(())
+            }
+            SerializeError::Tdb(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<T: fmt::Display>(msg: T) -> SerializeError {
+        SerializeError::invalid(msg.to_string())
+    }
+}
+
+/// A `Constructor` for records of type `T`.
+///
+/// The fields of the TrailDB are the fields of `T`, in declaration
+/// order, except for the UUID and timestamp which are taken from the
+/// fields named `uuid` and `time` (use `#[serde(rename = "...")]` or
+/// `uuid_key` and `timestamp_key` to pick others).
+///
+/// A UUID may be serialized as 16 bytes, such as a `[u8; 16]`, or as a
+/// string of 32 hex digits. Values are
+/// stored as text: numbers and booleans are formatted, unit enum
+/// variants become their name and `None` becomes the empty value.
+/// Sequences and tuples, such as `[u8; 16]` or `Vec<u8>`, are stored as
+/// binary values with one byte per element. Their elements must be
+/// numbers from 0 to 255, whatever their type, so a `Vec<u32>` holding
+/// `[104, 105]` is stored as `hi`.
+///
+/// Serde does not expose the fields of `T` without a value, so they
+/// are taken from the first record added, which also opens the
+/// constructor. Later records are matched to them by name, so map keys
+/// can come in any order. Fields that a later record leaves out, for
+/// example with `skip_serializing_if`, are empty; adding a record with
+/// a field the first one did not have fails with an error naming the
+/// field.
+///
+/// ```no_run
+/// # extern crate traildb;
+/// # #[macro_use] extern crate serde_derive;
+/// # use std::path::Path;
+/// use traildb::TypedConstructor;
+///
+/// #[derive(Serialize)]
+/// struct PageView<'a> {
+///     uuid: [u8; 16],
+///     time: u64,
+///     url: &'a str,
+///     duration: Option<u32>,
+/// }
+///
+/// # fn main() {
+/// let mut cons = TypedConstructor::new(Path::new("pageviews"));
+/// cons.add(&PageView { uuid: [0; 16], time: 1, url: "/", duration: None }).unwrap();
+/// cons.finalize().unwrap();
+/// # }
+/// ```
+pub struct TypedConstructor<T: ?Sized> {
+    path: PathBuf,
+    options: ConstructorOptions,
+    uuid_key: String,
+    timestamp_key: String,
+    fields: Vec<String>,
+    cons: Option<Constructor>,
+    _record: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize + ?Sized> TypedConstructor<T> {
+    pub fn new(path: &Path) -> Self {
+        TypedConstructor::with_options(path, &ConstructorOptions::new())
+    }
+
+    pub fn with_options(path: &Path, options: &ConstructorOptions) -> Self {
+        TypedConstructor {
+            path: path.to_path_buf(),
+            options: options.clone(),
+            uuid_key: UUID_KEY.to_owned(),
+            timestamp_key: TIMESTAMP_KEY.to_owned(),
+            fields: Vec::new(),
+            cons: None,
+            _record: PhantomData,
+        }
+    }
+
+    /// Take the UUID from the field `key` instead of `uuid`.
+    pub fn uuid_key(&mut self, key: &str) -> &mut Self {
+        self.uuid_key = key.to_owned();
+        self
+    }
+
+    /// Take the timestamp from the field `key` instead of `time`.
+    pub fn timestamp_key(&mut self, key: &str) -> &mut Self {
+        self.timestamp_key = key.to_owned();
+        self
+    }
+
+    /// The field names of the TrailDB. Empty until the first record
+    /// has been added.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Add a record. Its fields must be among those of the first one,
+    /// missing ones are empty.
+    pub fn add(&mut self, record: &T) -> Result<(), SerializeError> {
+        if self.cons.is_none() {
+            self.fields.clear();
+        }
+        let known = self.cons.is_some();
+        let values = if known { vec![Vec::new(); self.fields.len()] } else { Vec::new() };
+        let mut rec = Record {
+            uuid_key: &self.uuid_key,
+            timestamp_key: &self.timestamp_key,
+            fields: &mut self.fields,
+            known,
+            uuid: None,
+            timestamp: None,
+            values,
+            key: None,
+        };
+        record.serialize(&mut rec)?;
+        let uuid = rec.uuid.ok_or_else(|| SerializeError::invalid("missing value").in_field(rec.uuid_key))?;
+        let timestamp = rec.timestamp
+            .ok_or_else(|| SerializeError::invalid("missing value").in_field(rec.timestamp_key))?;
+        let values = rec.values;
+
+        if self.cons.is_none() {
+            self.cons = Some(self.open()?);
+        }
+        let cons = self.cons.as_mut().unwrap();
+        cons.add_values(&uuid, timestamp, &values).map_err(SerializeError::from)
+    }
+
+    /// Write the TrailDB to disk. If no records were added, the TrailDB
+    /// has no fields.
+    pub fn finalize(&mut self) -> Result<(), Error> {
+        if self.cons.is_none() {
+            self.cons = Some(self.open()?);
+        }
+        self.cons.as_mut().unwrap().finalize()
+    }
+
+    fn open(&self) -> Result<Constructor, Error> {
+        let fields: Vec<&str> = self.fields.iter().map(String::as_str).collect();
+        self.options.open(&self.path, &fields)
+    }
+}
+
+/// Collects the UUID, timestamp and values of one record.
+struct Record<'c> {
+    uuid_key: &'c str,
+    timestamp_key: &'c str,
+    fields: &'c mut Vec<String>,
+    /// Whether `fields` is fixed by an earlier record.
+    known: bool,
+    uuid: Option<Uuid>,
+    timestamp: Option<Timestamp>,
+    /// One value per field once `fields` is known, empty until set.
+    values: Vec<Vec<u8>>,
+    key: Option<String>,
+}
+
+impl<'c> Record<'c> {
+    fn field<V: Serialize + ?Sized>(&mut self, key: &str, value: &V) -> Result<(), SerializeError> {
+        let value = value.serialize(ValueSerializer).map_err(|e| e.in_field(key))?;
+        if key == self.uuid_key {
+            self.uuid = Some(parse_uuid(value).ok_or_else(|| SerializeError::invalid("invalid UUID").in_field(key))?);
+            return Ok(());
+        }
+        let value = value.into_bytes();
+        if key == self.timestamp_key {
+            let timestamp = str::from_utf8(&value).ok().and_then(|s| s.parse().ok());
+            self.timestamp = Some(timestamp.ok_or_else(|| SerializeError::invalid("invalid timestamp").in_field(key))?);
+        } else if !self.known {
+            self.fields.push(key.to_owned());
+            self.values.push(value);
+        } else {
+            let idx = self.fields
+                .iter()
+                .position(|field| field == key)
+                .ok_or_else(|| SerializeError::invalid("field is not in the TrailDB").in_field(key))?;
+            self.values[idx] = value;
+        }
+        Ok(())
+    }
+}
+
+/// A UUID is either 16 bytes or a string of 32 hex digits, which may
+/// contain dashes.
+fn parse_uuid(value: Value) -> Option<Uuid> {
+    let mut uuid = [0u8; 16];
+    let value = match value {
+        Value::Bytes(ref bytes) if bytes.len() == 16 => {
+            uuid.copy_from_slice(bytes);
+            return Some(uuid);
+        }
+        Value::Bytes(_) => return None,
+        Value::Text(text) => text,
+    };
+    let hex: Vec<u8> = value.into_iter().filter(|&c| c != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = str::from_utf8(pair).ok()?;
+        uuid[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+fn not_a_record() -> SerializeError {
+    SerializeError::invalid("a record must be a struct or a map")
+}
+
+impl<'r, 'c> ser::Serializer for &'r mut Record<'c> {
+    type Ok = ();
+    type Error = SerializeError;
+    type SerializeSeq = Impossible<(), SerializeError>;
+    type SerializeTuple = Impossible<(), SerializeError>;
+    type SerializeTupleStruct = Impossible<(), SerializeError>;
+    type SerializeTupleVariant = Impossible<(), SerializeError>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), SerializeError>;
+
+    fn serialize_bool(self, _v: bool) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_char(self, _v: char) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_str(self, _v: &str) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_none(self) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_some<V: Serialize + ?Sized>(self, value: &V) -> Result<(), SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _index: u32,
+                              _variant: &'static str)
+                              -> Result<(), SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_newtype_struct<V>(self, _name: &'static str, value: &V) -> Result<(), SerializeError>
+        where V: Serialize + ?Sized
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<V>(self,
+                                    _name: &'static str,
+                                    _index: u32,
+                                    _variant: &'static str,
+                                    _value: &V)
+                                    -> Result<(), SerializeError>
+        where V: Serialize + ?Sized
+    {
+        Err(not_a_record())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_tuple_struct(self,
+                              _name: &'static str,
+                              _len: usize)
+                              -> Result<Self::SerializeTupleStruct, SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _index: u32,
+                               _variant: &'static str,
+                               _len: usize)
+                               -> Result<Self::SerializeTupleVariant, SerializeError> {
+        Err(not_a_record())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, SerializeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerializeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _index: u32,
+                                _variant: &'static str,
+                                _len: usize)
+                                -> Result<Self::SerializeStructVariant, SerializeError> {
+        Err(not_a_record())
+    }
+}
+
+impl<'r, 'c> ser::SerializeStruct for &'r mut Record<'c> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<V>(&mut self, key: &'static str, value: &V) -> Result<(), SerializeError>
+        where V: Serialize + ?Sized
+    {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+impl<'r, 'c> ser::SerializeMap for &'r mut Record<'c> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<K: Serialize + ?Sized>(&mut self, key: &K) -> Result<(), SerializeError> {
+        let key = key.serialize(ValueSerializer)?.into_bytes();
+        let key = String::from_utf8(key).map_err(|_| SerializeError::invalid("field names must be UTF-8"))?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), SerializeError> {
+        let key = self.key.take().ok_or_else(|| SerializeError::invalid("value serialized before key"))?;
+        self.field(&key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+/// Serializes a single value to the bytes stored in the TrailDB.
+struct ValueSerializer;
+
+/// A serialized value, which remembers whether it was binary to tell
+/// raw UUIDs from hex strings.
+enum Value {
+    Text(Vec<u8>),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Value::Text(bytes) | Value::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+fn unsupported(what: &str) -> SerializeError {
+    SerializeError::invalid(format!("{} cannot be stored as a value", what))
+}
+
+macro_rules! serialize_display {
+    ($($method:ident: $ty:ty,)*) => {
+        $(
+            fn $method(self, value: $ty) -> Result<Value, SerializeError> {
+                Ok(Value::Text(value.to_string().into_bytes()))
+            }
+        )*
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    type SerializeSeq = BytesSerializer;
+    type SerializeTuple = BytesSerializer;
+    type SerializeTupleStruct = BytesSerializer;
+    type SerializeTupleVariant = Impossible<Value, SerializeError>;
+    type SerializeMap = Impossible<Value, SerializeError>;
+    type SerializeStruct = Impossible<Value, SerializeError>;
+    type SerializeStructVariant = Impossible<Value, SerializeError>;
+
+    serialize_display! {
+        serialize_bool: bool,
+        serialize_i8: i8,
+        serialize_i16: i16,
+        serialize_i32: i32,
+        serialize_i64: i64,
+        serialize_i128: i128,
+        serialize_u8: u8,
+        serialize_u16: u16,
+        serialize_u32: u32,
+        serialize_u64: u64,
+        serialize_u128: u128,
+        serialize_f32: f32,
+        serialize_f64: f64,
+        serialize_char: char,
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Value, SerializeError> {
+        Ok(Value::Text(value.as_bytes().to_vec()))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Value, SerializeError> {
+        Ok(Value::Bytes(value.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, SerializeError> {
+        Ok(Value::Text(Vec::new()))
+    }
+
+    fn serialize_some<V: Serialize + ?Sized>(self, value: &V) -> Result<Value, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, SerializeError> {
+        Ok(Value::Text(Vec::new()))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, SerializeError> {
+        Ok(Value::Text(Vec::new()))
+    }
+
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _index: u32,
+                              variant: &'static str)
+                              -> Result<Value, SerializeError> {
+        Ok(Value::Text(variant.as_bytes().to_vec()))
+    }
+
+    fn serialize_newtype_struct<V>(self, _name: &'static str, value: &V) -> Result<Value, SerializeError>
+        where V: Serialize + ?Sized
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<V>(self,
+                                    _name: &'static str,
+                                    _index: u32,
+                                    _variant: &'static str,
+                                    _value: &V)
+                                    -> Result<Value, SerializeError>
+        where V: Serialize + ?Sized
+    {
+        Err(unsupported("an enum variant with data"))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<BytesSerializer, SerializeError> {
+        Ok(BytesSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<BytesSerializer, SerializeError> {
+        Ok(BytesSerializer(Vec::with_capacity(len)))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<BytesSerializer, SerializeError> {
+        Ok(BytesSerializer(Vec::with_capacity(len)))
+    }
+
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _index: u32,
+                               _variant: &'static str,
+                               _len: usize)
+                               -> Result<Self::SerializeTupleVariant, SerializeError> {
+        Err(unsupported("an enum variant with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> {
+        Err(unsupported("a map"))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, SerializeError> {
+        Err(unsupported("a struct"))
+    }
+
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _index: u32,
+                                _variant: &'static str,
+                                _len: usize)
+                                -> Result<Self::SerializeStructVariant, SerializeError> {
+        Err(unsupported("an enum variant with data"))
+    }
+}
+
+/// Serializes a sequence of bytes, such as a `[u8; 16]`, as a binary
+/// value.
+struct BytesSerializer(Vec<u8>);
+
+impl BytesSerializer {
+    fn push<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), SerializeError> {
+        let value = value.serialize(ValueSerializer)?.into_bytes();
+        let byte = str::from_utf8(&value).ok().and_then(|s| s.parse().ok());
+        self.0.push(byte.ok_or_else(|| unsupported("a sequence of non-bytes"))?);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for BytesSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+
+    fn serialize_element<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), SerializeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::Bytes(self.0))
+    }
+}
+
+impl ser::SerializeTuple for BytesSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+
+    fn serialize_element<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), SerializeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::Bytes(self.0))
+    }
+}
+
+impl ser::SerializeTupleStruct for BytesSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+
+    fn serialize_field<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), SerializeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::Bytes(self.0))
+    }
+}
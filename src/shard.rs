@@ -0,0 +1,124 @@
+use std::path::Path;
+use std::str::Utf8Error;
+
+use {Db, Error, Event, EventRecord, MultiCursor, Uuid};
+
+/// A set of TrailDBs holding parts of the same trails, e.g. one
+/// database per day.
+///
+/// ```no_run
+/// # use traildb::ShardSet;
+/// let shards = ShardSet::open(&["2024-01-01.tdb", "2024-01-02.tdb"]).unwrap();
+/// let uuid = [0; 16];
+/// let mut trail = shards.trail_for_uuid(&uuid);
+/// while let Some(event) = trail.next() {
+///     let record = event.decode().unwrap();
+///     println!("shard {} at {}: {:?}", event.shard, record.timestamp, record.iter().collect::<Vec<_>>());
+/// }
+/// ```
+pub struct ShardSet {
+    shards: Vec<Db>,
+}
+
+impl ShardSet {
+    /// Open the databases at `paths`. Shards are numbered in the order
+    /// given.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<ShardSet, Error> {
+        let shards = paths.iter().map(Db::open).collect::<Result<_, _>>()?;
+        Ok(ShardSet { shards })
+    }
+
+    /// Use already opened databases as shards.
+    pub fn from_dbs(shards: Vec<Db>) -> ShardSet {
+        ShardSet { shards }
+    }
+
+    /// Number of shards.
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Whether there are no shards.
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// The shard at `shard`.
+    pub fn shard(&self, shard: usize) -> Option<&Db> {
+        self.shards.get(shard)
+    }
+
+    /// All shards, in order.
+    pub fn shards(&self) -> &[Db] {
+        &self.shards
+    }
+
+    /// The events of the trail of `uuid` in all shards, in timestamp
+    /// order. Shards without the trail are skipped.
+    pub fn trail_for_uuid(&self, uuid: &Uuid) -> ShardTrail<'_> {
+        let mut cursors = vec![];
+        let mut shards = vec![];
+        for (shard, db) in self.shards.iter().enumerate() {
+            if let Some(trail_id) = db.get_trail_id(uuid) {
+                let mut cursor = db.cursor();
+                if cursor.get_trail(trail_id).is_ok() {
+                    cursors.push(cursor);
+                    shards.push(shard);
+                }
+            }
+        }
+        ShardTrail {
+            cursor: MultiCursor::new(cursors),
+            shards,
+            set: self,
+        }
+    }
+}
+
+/// The events of one trail across a `ShardSet`, created by
+/// `ShardSet::trail_for_uuid`.
+pub struct ShardTrail<'a> {
+    cursor: MultiCursor<'a>,
+    shards: Vec<usize>,
+    set: &'a ShardSet,
+}
+
+impl<'a> ShardTrail<'a> {
+    /// The shards that have the trail.
+    pub fn shards(&self) -> &[usize] {
+        &self.shards
+    }
+
+    /// The next event of the trail. Like `Cursor::next`, the event
+    /// borrows the trail.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<ShardEvent<'_>> {
+        let multi_event = self.cursor.next()?;
+        let shard = self.shards[multi_event.cursor_idx];
+        Some(ShardEvent {
+            shard,
+            event: multi_event.event,
+            db: &self.set.shards[shard],
+        })
+    }
+}
+
+/// An event of a `ShardTrail`, with the shard it was read from.
+pub struct ShardEvent<'a> {
+    /// The index of the shard in the `ShardSet`.
+    pub shard: usize,
+    pub event: Event<'a>,
+    db: &'a Db,
+}
+
+impl<'a> ShardEvent<'a> {
+    /// The database of the shard.
+    pub fn db(&self) -> &'a Db {
+        self.db
+    }
+
+    /// Look up the field names and values of the event in its shard.
+    pub fn decode(&self) -> Result<EventRecord<'a>, Utf8Error> {
+        self.event.decode(self.db)
+    }
+}